@@ -1,46 +1,1072 @@
+use std::ffi::OsStr;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufReader, Read, Seek};
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use rodio::cpal::traits::{DeviceTrait, HostTrait};
 use rodio::decoder::DecoderError;
-use rodio::{Decoder, Sink};
+use rodio::queue::SourcesQueueOutput;
+use rodio::source::{SeekError, UniformSourceIterator};
+use rodio::{cpal, Decoder, OutputStream, OutputStreamHandle, Sample, Sink, Source};
 
 use crate::playlist::{PlaylistConfig, SongConfig};
 use crate::LibError;
 
-pub fn play(
-    file: File, sink: &Sink, song_config: &SongConfig, global_config: &PlaylistConfig,
-) -> Result<(), LibError> {
-    let buf = BufReader::new(file);
+///Streams packet by packet, never holds the whole file
+pub fn decode(file: File) -> Result<Decoder<BufReader<File>>, LibError> {
+    decode_reader(BufReader::new(file))
+}
 
-    let source = Decoder::new(buf);
+fn decode_reader<R>(reader: R) -> Result<Decoder<R>, LibError>
+where
+    R: Read + Seek + Send + Sync + 'static,
+{
+    match Decoder::new(reader) {
+        Ok(s) => Ok(s),
+        Err(DecoderError::UnrecognizedFormat) => Err(LibError::DecodeFailed(String::from(
+            "Unrecognized Format, skipping.",
+        ))),
+        // Can't happen if no audio format is built in
+        #[allow(unreachable_patterns)]
+        Err(e) => Err(LibError::DecodeFailed(format!(
+            "Unknown Error: {e}, skipping."
+        ))),
+    }
+}
 
-    let source = match source {
-        Ok(s) => s,
-        Err(DecoderError::UnrecognizedFormat) => {
-            return Err(LibError::new(String::from(
-                "Unrecognized Format, skipping.",
-            )));
-        }
-        Err(e) => {
-            return Err(LibError::new(format!("Unknown Error: {e}, skipping.")));
+///Like `decode`, but explains failures for formats left out of this build
+pub fn open(path: &Path) -> Result<Decoder<BufReader<File>>, LibError> {
+    let file = File::open(path).map_err(|e| LibError::Io("Unable to open audio file", e))?;
+    decode(file).map_err(|e| match disabled_format(path) {
+        Some(f) => LibError::DecodeFailed(format!("{} support is not built in, skipping.", f.name)),
+        None => e,
+    })
+}
+
+///The format this file most likely is, if this build can't decode it
+fn disabled_format(path: &Path) -> Option<&'static Format> {
+    let extension = path.extension()?.to_str()?.to_lowercase();
+    FORMATS
+        .iter()
+        .find(|f| !f.enabled && f.extensions.contains(&extension.as_str()))
+}
+
+///A decoded song, as it is played
+pub type SongSource = Box<dyn Source<Item = i16> + Send>;
+
+///Trim the song and change its tempo
+pub fn prepare(
+    source: Decoder<BufReader<File>>, config: &SongConfig, tempo: Option<f32>,
+) -> SongSource {
+    let source = Trim::new(source, config.start, config.end);
+    #[cfg(feature = "tempo")]
+    if let Some(tempo) = tempo {
+        return Box::new(crate::tempo::TimeStretch::new(source, tempo));
+    }
+    #[cfg(not(feature = "tempo"))]
+    let _ = tempo;
+    Box::new(source)
+}
+
+///What playback does with a sink, so tests can play into a fake one
+pub trait AudioSink: Send + Sync {
+    fn append(&self, source: SongSource);
+    fn volume(&self) -> f32;
+    fn set_volume(&self, volume: f32);
+    fn set_speed(&self, speed: f32);
+    fn pause(&self);
+    fn play(&self);
+    fn is_paused(&self) -> bool;
+    ///Remove all songs, which also pauses
+    fn clear(&self);
+    fn get_pos(&self) -> Duration;
+    fn try_seek(&self, pos: Duration) -> Result<(), SeekError>;
+}
+
+impl AudioSink for Sink {
+    fn append(&self, source: SongSource) {
+        Sink::append(self, source);
+    }
+    fn volume(&self) -> f32 {
+        Sink::volume(self)
+    }
+    fn set_volume(&self, volume: f32) {
+        Sink::set_volume(self, volume);
+    }
+    fn set_speed(&self, speed: f32) {
+        Sink::set_speed(self, speed);
+    }
+    fn pause(&self) {
+        Sink::pause(self);
+    }
+    fn play(&self) {
+        Sink::play(self);
+    }
+    fn is_paused(&self) -> bool {
+        Sink::is_paused(self)
+    }
+    fn clear(&self) {
+        Sink::clear(self);
+    }
+    fn get_pos(&self) -> Duration {
+        Sink::get_pos(self)
+    }
+    fn try_seek(&self, pos: Duration) -> Result<(), SeekError> {
+        Sink::try_seek(self, pos)
+    }
+}
+
+///Sink for tests, that records what is done to it. Songs end as soon as they are appended.
+#[cfg(test)]
+#[derive(Default)]
+pub struct FakeSink {
+    ///Names of the methods called, in order
+    pub calls: Mutex<Vec<&'static str>>,
+    volume: Mutex<f32>,
+    paused: std::sync::atomic::AtomicBool,
+}
+
+#[cfg(test)]
+impl FakeSink {
+    fn record(&self, call: &'static str) {
+        self.calls.lock().unwrap().push(call);
+    }
+    pub fn count(&self, call: &str) -> usize {
+        self.calls
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|&&c| c == call)
+            .count()
+    }
+}
+
+#[cfg(test)]
+impl AudioSink for FakeSink {
+    fn append(&self, _source: SongSource) {
+        self.record("append");
+    }
+    fn volume(&self) -> f32 {
+        *self.volume.lock().unwrap()
+    }
+    fn set_volume(&self, volume: f32) {
+        self.record("set_volume");
+        *self.volume.lock().unwrap() = volume;
+    }
+    fn set_speed(&self, _speed: f32) {
+        self.record("set_speed");
+    }
+    fn pause(&self) {
+        self.record("pause");
+        self.paused.store(true, Ordering::SeqCst);
+    }
+    fn play(&self) {
+        self.record("play");
+        self.paused.store(false, Ordering::SeqCst);
+    }
+    fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+    fn clear(&self) {
+        self.record("clear");
+        self.paused.store(true, Ordering::SeqCst);
+    }
+    fn get_pos(&self) -> Duration {
+        Duration::ZERO
+    }
+    fn try_seek(&self, _pos: Duration) -> Result<(), SeekError> {
+        self.record("try_seek");
+        Ok(())
+    }
+}
+
+///A song appended to a sink
+pub struct Queued {
+    pub duration: Option<Duration>,
+    ///Samples per second, to convert positions
+    rate: u64,
+    ///Samples played so far
+    position: Arc<AtomicU64>,
+    ///Sample the song stops at, can be moved while playing
+    end: Arc<AtomicU64>,
+    ended: Receiver<()>,
+}
+
+impl Queued {
+    ///Blocks until the song has ended or was removed from the sink.
+    pub fn wait(&self) {
+        let _ = self.ended.recv();
+    }
+    pub fn position(&self) -> Duration {
+        from_samples(self.position.load(Ordering::SeqCst), self.rate)
+    }
+    ///Stop playing the song at `pos`, as the rest of it is played by the next one.
+    pub fn cut(&self, pos: Duration) {
+        self.end.store(to_samples(pos, self.rate), Ordering::SeqCst);
+    }
+}
+
+///End of the previous song, mixed into the start of the next one
+pub struct Tail {
+    pub source: SongSource,
+    ///Position in the previous song the tail starts at
+    pub start: Duration,
+    pub duration: Duration,
+    ///Volume relative to the next song
+    pub gain: f32,
+}
+
+///Append a song to the sink. If `tail` is given, crossfade from it instead of fading in.
+///Songs don't fade out if `crossfade` is set, as they may be cut later.
+///A `limiter` amplifies the song by its boost, see `config_sink`.
+pub fn queue(
+    sink: &dyn AudioSink, mut source: SongSource, tail: Option<Tail>, fade: Option<Duration>,
+    crossfade: bool, limiter: Option<&Boost>,
+) -> Queued {
+    let duration = source.total_duration();
+    let rate = u64::from(source.sample_rate()) * u64::from(source.channels());
+
+    if let Some(tail) = tail {
+        source = Box::new(Crossfade::new(source, tail));
+    } else if let Some(fade) = fade {
+        source = Box::new(source.fade_in(fade));
+    }
+    if let Some(fade) = fade.filter(|_| !crossfade) {
+        source = Box::new(FadeOut::new(source, fade));
+    }
+    if let Some(boost) = limiter {
+        source = Box::new(Limiter {
+            input: source,
+            boost: boost.clone(),
+        });
+    }
+
+    let position = Arc::new(AtomicU64::new(0));
+    let end = Arc::new(AtomicU64::new(u64::MAX));
+    let (done, ended) = mpsc::channel();
+    sink.append(Box::new(Cut {
+        input: source,
+        rate,
+        position: Arc::clone(&position),
+        end: Arc::clone(&end),
+        _done: done,
+    }));
+    Queued {
+        duration,
+        rate,
+        position,
+        end,
+        ended,
+    }
+}
+
+///Apply what `queue` and the sink apply while playing: fades, speed and volume.
+///Crossfades are left out, as they depend on how far the previous song was played.
+pub fn apply_config(
+    source: SongSource, song: &SongConfig, config: &PlaylistConfig, max_volume: f32,
+) -> SongSource {
+    let mut source = source;
+    if let Some(fade) = config.fade {
+        source = Box::new(FadeOut::new(source.fade_in(fade), fade));
+    }
+    let source = source.speed(song.speed);
+    let volume = (song.volume * config.volume).min(max_volume);
+    if config.limiter && volume > 1.0 {
+        let boost = Boost::new();
+        boost.set(volume);
+        Box::new(Limiter {
+            input: source,
+            boost,
+        })
+    } else {
+        Box::new(source.amplify(volume))
+    }
+}
+
+///Names of all output devices of the default host
+pub fn device_names() -> Result<Vec<String>, LibError> {
+    let devices = cpal::default_host()
+        .output_devices()
+        .map_err(|e| LibError::AudioDevice("Unable to list audio devices", Box::new(e)))?;
+    Ok(devices.filter_map(|d| d.name().ok()).collect())
+}
+
+///Stream to the output device called `device`, or the default device if there is none by that name
+pub fn open_stream(device: Option<&str>) -> Result<(OutputStream, OutputStreamHandle), LibError> {
+    let found = device.and_then(|name| {
+        let device = cpal::default_host()
+            .output_devices()
+            .ok()?
+            .find(|d| d.name().is_ok_and(|n| n == name));
+        if device.is_none() {
+            eprintln!("Audio device not found, using default: {name}");
         }
+        device
+    });
+    let stream = match found {
+        Some(d) => OutputStream::try_from_device(&d),
+        None => OutputStream::try_default(),
     };
+    stream.map_err(|e| LibError::AudioDevice("Unable to create audio stream", Box::new(e)))
+}
+
+///Output of a sink that can move from one audio stream to the next
+#[derive(Clone)]
+struct SharedOutput(Arc<Mutex<SourcesQueueOutput<f32>>>);
+
+impl SharedOutput {
+    ///Play `duration` of the output into nothing
+    fn drain(&self, duration: Duration) {
+        let mut output = self.0.lock().unwrap();
+        let rate = f64::from(output.sample_rate()) * f64::from(output.channels());
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let samples = (rate * duration.as_secs_f64()) as usize;
+        output.by_ref().take(samples).for_each(drop);
+    }
+}
+
+impl Iterator for SharedOutput {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        self.0.lock().unwrap().next()
+    }
+}
+
+impl Source for SharedOutput {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.0.lock().unwrap().current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.0.lock().unwrap().channels()
+    }
 
-    config_sink(sink, song_config, global_config);
-    sink.append(source);
-    sink.sleep_until_end();
+    fn sample_rate(&self) -> u32 {
+        self.0.lock().unwrap().sample_rate()
+    }
 
-    Ok(())
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+///Audio device a sink plays on. It can be released while the sink is paused for long,
+///so other programs can use it, and is opened again once the sink plays again.
+pub struct Device<'a> {
+    name: Option<&'a str>,
+    output: SharedOutput,
+    ///None while released
+    stream: Option<(OutputStream, OutputStreamHandle)>,
+    release_after: Option<Duration>,
+    paused_since: Option<Instant>,
+}
+
+impl<'a> Device<'a> {
+    ///Play `output` on the device called `name`, see `open_stream`.
+    ///The device is released after being paused for `release_after`, if set.
+    pub fn open(
+        name: Option<&'a str>, output: SourcesQueueOutput<f32>, release_after: Option<Duration>,
+    ) -> Result<Self, LibError> {
+        let output = SharedOutput(Arc::new(Mutex::new(output)));
+        let stream = Device::connect(name, &output)?;
+        Ok(Device {
+            name,
+            output,
+            stream: Some(stream),
+            release_after,
+            paused_since: None,
+        })
+    }
+
+    fn connect(
+        name: Option<&str>, output: &SharedOutput,
+    ) -> Result<(OutputStream, OutputStreamHandle), LibError> {
+        let (stream, handle) = open_stream(name)?;
+        handle
+            .play_raw(output.clone())
+            .map_err(|e| LibError::AudioDevice("Unable to start audio stream", Box::new(e)))?;
+        Ok((stream, handle))
+    }
+
+    ///Release or reopen the device, depending on how long `sink` is paused.
+    ///Has to be called regularly, every `elapsed`. While released, the output is played into nothing,
+    ///so the sink keeps following controls like seeking.
+    pub fn update(&mut self, sink: &dyn AudioSink, elapsed: Duration) -> Result<(), LibError> {
+        if sink.is_paused() {
+            let since = *self.paused_since.get_or_insert_with(Instant::now);
+            if self.release_after.is_some_and(|r| since.elapsed() >= r) {
+                self.stream = None;
+            }
+        } else {
+            self.paused_since = None;
+            if self.stream.is_none() {
+                match Device::connect(self.name, &self.output) {
+                    Ok(stream) => self.stream = Some(stream),
+                    Err(e) => {
+                        self.output.drain(elapsed);
+                        return Err(e);
+                    }
+                }
+            }
+        }
+        if self.stream.is_none() {
+            self.output.drain(elapsed);
+        }
+        Ok(())
+    }
+}
+
+///Duration of the whole file, if it can be decoded and the format tells
+pub fn duration(path: &Path) -> Option<Duration> {
+    open(path).ok()?.total_duration()
+}
+
+///Like `valid_audio_file`, also returns the duration of the file if known
+pub fn probe(file: File) -> Result<Option<Duration>, LibError> {
+    decode(file).map(|d| d.total_duration())
 }
 
 ///Can we decode this file? Does not necessarily mean we can play it to the end.
 pub fn valid_audio_file(file: File) -> bool {
-    let buf = BufReader::new(file);
-    let source = Decoder::new(buf);
+    probe(file).is_ok()
+}
+
+///How long a song of `total` length plays, after trimming and changing its tempo
+pub fn played_duration(total: Duration, config: &SongConfig, tempo: Option<f32>) -> Duration {
+    let end = config.end.map_or(total, |end| end.min(total));
+    let duration = end.saturating_sub(config.start.unwrap_or_default());
+    match tempo {
+        Some(tempo) if cfg!(feature = "tempo") => duration.div_f32(tempo),
+        _ => duration,
+    }
+}
+
+///A format `Decoder` supports
+pub struct Format {
+    pub name: &'static str,
+    ///Extensions of files we expect to be in this format
+    pub extensions: &'static [&'static str],
+    ///Whether this build can decode it, see the features in Cargo.toml
+    pub enabled: bool,
+    ///Whether a file starting with these bytes is in this format, see `HEADER_LEN`
+    magic: fn(&[u8]) -> bool,
+}
+
+///Bytes at the start of a file `Format::magic` looks at
+const HEADER_LEN: u64 = 36;
+
+///MPEG audio frames start with 11 set bits. Layer 0 is reserved, it marks AAC in ADTS instead.
+fn frame_sync(header: &[u8], mp3: bool) -> bool {
+    match header {
+        [0xFF, b, ..] if b & 0xE0 == 0xE0 => (b & 0x06 != 0) == mp3,
+        _ => false,
+    }
+}
+
+pub const FORMATS: [Format; 5] = [
+    Format {
+        name: "MP3",
+        extensions: &["mp3"],
+        enabled: cfg!(feature = "mp3"),
+        magic: |h| h.starts_with(b"ID3") || frame_sync(h, true),
+    },
+    Format {
+        name: "FLAC",
+        extensions: &["flac"],
+        enabled: cfg!(feature = "flac"),
+        magic: |h| h.starts_with(b"fLaC"),
+    },
+    Format {
+        name: "WAV",
+        extensions: &["wav"],
+        enabled: cfg!(feature = "wav"),
+        magic: |h| h.starts_with(b"RIFF") && h.get(8..12) == Some(b"WAVE"),
+    },
+    Format {
+        name: "Ogg Vorbis",
+        extensions: &["ogg"],
+        enabled: cfg!(feature = "vorbis"),
+        magic: |h| h.starts_with(b"OggS") && h.get(28..35) == Some(b"\x01vorbis"),
+    },
+    Format {
+        name: "AAC",
+        extensions: &["m4a", "aac"],
+        enabled: cfg!(feature = "aac"),
+        magic: |h| h.get(4..8) == Some(b"ftyp") || frame_sync(h, false),
+    },
+];
+
+///Check the first bytes of the file instead of decoding it, which is much faster.
+///Only files that start like no format but have the extension of one are decoded.
+///Says nothing about whether the rest of the file is valid.
+pub fn quick_check(path: &Path) -> Result<(), LibError> {
+    let file = File::open(path).map_err(|e| LibError::Io("Unable to open audio file", e))?;
+    let mut header = Vec::new();
+    file.take(HEADER_LEN)
+        .read_to_end(&mut header)
+        .map_err(|e| LibError::Io("Unable to read audio file", e))?;
+    match FORMATS.iter().find(|f| (f.magic)(&header)) {
+        Some(f) if f.enabled => Ok(()),
+        Some(f) => Err(LibError::DecodeFailed(format!(
+            "{} support is not built in",
+            f.name
+        ))),
+        None if has_audio_extension(path) => open(path).map(drop),
+        None => Err(LibError::DecodeFailed(String::from(
+            "Not an audio file in a supported format",
+        ))),
+    }
+}
+
+fn has_audio_extension(path: &Path) -> bool {
+    path.extension()
+        .and_then(OsStr::to_str)
+        .map(str::to_lowercase)
+        .is_some_and(|e| {
+            FORMATS
+                .iter()
+                .any(|f| f.enabled && f.extensions.contains(&e.as_str()))
+        })
+}
+
+///Cheaper than `valid_audio_file`, only decodes files without a known audio extension.
+pub fn likely_audio_file(path: &Path) -> bool {
+    has_audio_extension(path) || File::open(path).is_ok_and(valid_audio_file)
+}
+
+///A muted sink stays silent, the volume is restored by configuring it again once unmuted.
+///The combined volume is never louder than `max_volume`, returns whether it had to be limited.
+///With the limiter on, amplification above unity is left to the limiter through `boost`.
+pub fn config_sink(
+    sink: &dyn AudioSink, song_config: &SongConfig, global_config: &PlaylistConfig, muted: bool,
+    max_volume: f32, boost: &Boost,
+) -> bool {
+    let volume = song_config.volume * global_config.volume;
+    let limited = volume.min(max_volume);
+    if muted {
+        sink.set_volume(0.0);
+    } else if global_config.limiter {
+        sink.set_volume(limited.min(1.0));
+        boost.set(limited.max(1.0));
+    } else {
+        sink.set_volume(limited);
+    }
+    sink.set_speed(song_config.speed);
+    volume > max_volume
+}
+
+///Volume factor that changes the level by `db` decibels, -6 dB about halves it
+#[must_use]
+pub fn db_to_volume(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+///Level change of a volume factor in decibels, minus infinity if silent
+#[must_use]
+pub fn volume_to_db(volume: f32) -> f32 {
+    20.0 * volume.log10()
+}
+
+///Amplification above unity, shared between the sink config and the limiters of queued songs
+#[derive(Clone)]
+pub struct Boost(Arc<AtomicU32>);
+
+impl Boost {
+    pub fn new() -> Self {
+        Boost(Arc::new(AtomicU32::new(1f32.to_bits())))
+    }
+    fn set(&self, gain: f32) {
+        self.0.store(gain.to_bits(), Ordering::Relaxed);
+    }
+    pub(crate) fn get(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+///Amplifies by `boost`, bending peaks above `LIMITER_KNEE` softly towards full scale instead of
+///clipping them. Samples pass unchanged as long as there is no boost, otherwise every sample
+///costs a few float operations, and a `tanh` once it is above the knee.
+struct Limiter<I> {
+    input: I,
+    boost: Boost,
+}
+
+///Fraction of full scale above which the limiter starts compressing
+const LIMITER_KNEE: f32 = 0.8;
+
+fn soft_clip(x: f32) -> f32 {
+    let level = x.abs();
+    if level <= LIMITER_KNEE {
+        return x;
+    }
+    let headroom = 1.0 - LIMITER_KNEE;
+    let level = LIMITER_KNEE + headroom * ((level - LIMITER_KNEE) / headroom).tanh();
+    level.copysign(x)
+}
+
+impl<I> Iterator for Limiter<I>
+where
+    I: Source<Item = i16>,
+{
+    type Item = i16;
 
-    source.is_ok()
+    #[allow(clippy::cast_possible_truncation)]
+    fn next(&mut self) -> Option<i16> {
+        let sample = self.input.next()?;
+        let boost = self.boost.get();
+        if boost <= 1.0 {
+            return Some(sample);
+        }
+        let x = f32::from(sample) / f32::from(i16::MAX) * boost;
+        Some((soft_clip(x) * f32::from(i16::MAX)) as i16)
+    }
+}
+
+impl<I> Source for Limiter<I>
+where
+    I: Source<Item = i16>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.input.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.input.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.input.try_seek(pos)
+    }
+}
+
+///Plays only the part of the input between `start` and `end`, as if it was all there is.
+///Positions, seeking and the total duration are relative to `start`.
+struct Trim<I> {
+    input: I,
+    start: Duration,
+    end: Option<Duration>,
+    rate: u64,
+    ///Samples played since `start`
+    position: u64,
+    ///Samples from `start` to `end`
+    len: u64,
+}
+
+impl<I> Trim<I>
+where
+    I: Source,
+    I::Item: Sample,
+{
+    fn new(mut input: I, start: Option<Duration>, end: Option<Duration>) -> Self {
+        let start = start.unwrap_or_default();
+        let rate = u64::from(input.sample_rate()) * u64::from(input.channels());
+        let channels = u64::from(input.channels()).max(1);
+        if !start.is_zero() && input.try_seek(start).is_err() {
+            // Not every format can seek, so we skip the samples instead
+            for _ in 0..to_samples(start, rate) / channels * channels {
+                if input.next().is_none() {
+                    break;
+                }
+            }
+        }
+        let len = end.map_or(u64::MAX, |end| {
+            to_samples(end.saturating_sub(start), rate) / channels * channels
+        });
+        Trim {
+            input,
+            start,
+            end,
+            rate,
+            position: 0,
+            len,
+        }
+    }
+}
+
+impl<I> Iterator for Trim<I>
+where
+    I: Source,
+    I::Item: Sample,
+{
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<I::Item> {
+        if self.position >= self.len {
+            return None;
+        }
+        self.position += 1;
+        self.input.next()
+    }
 }
 
-pub fn config_sink(sink: &Sink, song_config: &SongConfig, global_config: &PlaylistConfig) {
-    sink.set_volume(song_config.volume * global_config.volume);
+impl<I> Source for Trim<I>
+where
+    I: Source,
+    I::Item: Sample,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.input.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.input.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        let total = self.input.total_duration()?;
+        let end = self.end.map_or(total, |end| end.min(total));
+        Some(end.saturating_sub(self.start))
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.input.try_seek(self.start + pos)?;
+        self.position = to_samples(pos, self.rate);
+        Ok(())
+    }
+}
+
+///Fades out the last `duration` of the source. Does nothing if the total duration is unknown.
+struct FadeOut<I> {
+    input: I,
+    rate: u64,
+    ///Samples played so far
+    position: u64,
+    fade_start: u64,
+    fade_len: u64,
+}
+
+impl<I> FadeOut<I>
+where
+    I: Source,
+    I::Item: Sample,
+{
+    fn new(input: I, duration: Duration) -> Self {
+        let rate = u64::from(input.sample_rate()) * u64::from(input.channels());
+        let (fade_start, fade_len) = match input.total_duration() {
+            Some(total) => {
+                let start = to_samples(total.saturating_sub(duration), rate);
+                (start, to_samples(total, rate) - start)
+            }
+            None => (u64::MAX, 0),
+        };
+        FadeOut {
+            input,
+            rate,
+            position: 0,
+            fade_start,
+            fade_len,
+        }
+    }
+}
+
+///`rate` is in samples per second, counting every channel
+fn to_samples(d: Duration, rate: u64) -> u64 {
+    u64::try_from(d.as_nanos() * u128::from(rate) / 1_000_000_000).unwrap_or(u64::MAX)
+}
+
+fn from_samples(samples: u64, rate: u64) -> Duration {
+    let nanos = u128::from(samples) * 1_000_000_000 / u128::from(rate.max(1));
+    Duration::from_nanos(u64::try_from(nanos).unwrap_or(u64::MAX))
+}
+
+impl<I> Iterator for FadeOut<I>
+where
+    I: Source,
+    I::Item: Sample,
+{
+    type Item = I::Item;
+
+    #[allow(clippy::cast_precision_loss)]
+    fn next(&mut self) -> Option<I::Item> {
+        let sample = self.input.next()?;
+        self.position += 1;
+        if self.position <= self.fade_start || self.fade_len == 0 {
+            return Some(sample);
+        }
+        let remaining = (self.fade_start + self.fade_len).saturating_sub(self.position);
+        Some(sample.amplify(remaining as f32 / self.fade_len as f32))
+    }
+}
+
+impl<I> Source for FadeOut<I>
+where
+    I: Source,
+    I::Item: Sample,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.input.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.input.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.input.try_seek(pos)?;
+        self.position = to_samples(pos, self.rate);
+        Ok(())
+    }
+}
+
+///Mixes the end of the previous song into the start of `input`, fading one out and the other in.
+///If `input` is shorter than the tail, the rest of the tail is cut off.
+struct Crossfade {
+    input: SongSource,
+    tail: UniformSourceIterator<SongSource, i16>,
+    tail_start: Duration,
+    rate: u64,
+    ///Samples of `input` played so far
+    position: u64,
+    fade_len: u64,
+}
+
+impl Crossfade {
+    fn new(input: SongSource, tail: Tail) -> Self {
+        let rate = u64::from(input.sample_rate()) * u64::from(input.channels());
+        let tail_source: SongSource = Box::new(tail.source.amplify(tail.gain));
+        Crossfade {
+            tail: UniformSourceIterator::new(tail_source, input.channels(), input.sample_rate()),
+            tail_start: tail.start,
+            rate,
+            position: 0,
+            fade_len: to_samples(tail.duration, rate),
+            input,
+        }
+    }
+}
+
+impl Iterator for Crossfade {
+    type Item = i16;
+
+    #[allow(clippy::cast_precision_loss)]
+    fn next(&mut self) -> Option<i16> {
+        let sample = self.input.next()?;
+        self.position += 1;
+        if self.position > self.fade_len {
+            return Some(sample);
+        }
+        let in_gain = self.position as f32 / self.fade_len as f32;
+        let tail = self.tail.next().unwrap_or(0).amplify(1.0 - in_gain);
+        Some(sample.amplify(in_gain).saturating_add(tail))
+    }
+}
+
+impl Source for Crossfade {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.input.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.input.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.input.try_seek(pos)?;
+        // Keep the channels of both sources lined up
+        let channels = u64::from(self.input.channels());
+        self.position = to_samples(pos, self.rate) / channels * channels;
+        if self.position < self.fade_len {
+            // If this fails the tail is just out of sync for the rest of the crossfade
+            let _ = self.tail.try_seek(self.tail_start + pos);
+        }
+        Ok(())
+    }
+}
+
+///Can end the source early while playing. Dropping it signals that the source is done playing.
+struct Cut {
+    input: SongSource,
+    rate: u64,
+    ///Samples played so far
+    position: Arc<AtomicU64>,
+    end: Arc<AtomicU64>,
+    _done: Sender<()>,
+}
+
+impl Iterator for Cut {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        if self.position.fetch_add(1, Ordering::SeqCst) >= self.end.load(Ordering::SeqCst) {
+            return None;
+        }
+        self.input.next()
+    }
+}
+
+impl Source for Cut {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.input.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.input.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.input.try_seek(pos)?;
+        self.position
+            .store(to_samples(pos, self.rate), Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decibels() {
+        assert!((db_to_volume(0.0) - 1.0).abs() < f32::EPSILON);
+        assert!((db_to_volume(-6.0) - 0.5).abs() < 0.01);
+        assert!((db_to_volume(20.0) - 10.0).abs() < 0.001);
+        assert!((volume_to_db(0.5) + 6.0).abs() < 0.1);
+        assert!((volume_to_db(db_to_volume(3.5)) - 3.5).abs() < 0.001);
+        assert!(volume_to_db(0.0).is_infinite());
+    }
+
+    #[test]
+    fn soft_clip_keeps_quiet_samples() {
+        assert!((soft_clip(0.5) - 0.5).abs() < f32::EPSILON);
+        assert!((soft_clip(-0.8) + 0.8).abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn soft_clip_stays_below_full_scale() {
+        for x in [0.9, 1.0, 2.0, 10.0] {
+            let y = soft_clip(x);
+            assert!(y > LIMITER_KNEE && y <= 1.0);
+            assert!((soft_clip(-x) + y).abs() < f32::EPSILON);
+        }
+        assert!(soft_clip(0.9) < soft_clip(2.0));
+    }
+
+    #[test]
+    fn magic_bytes() {
+        let format = |header: &[u8]| FORMATS.iter().find(|f| (f.magic)(header)).map(|f| f.name);
+        assert_eq!(format(b"ID3\x04"), Some("MP3"));
+        assert_eq!(format(&[0xFF, 0xFB, 0x90]), Some("MP3"));
+        assert_eq!(format(&[0xFF, 0xF1, 0x50]), Some("AAC"));
+        assert_eq!(format(b"\0\0\0\x20ftypM4A "), Some("AAC"));
+        assert_eq!(format(b"fLaC\0\0\0\x22"), Some("FLAC"));
+        assert_eq!(format(b"RIFF\x24\0\0\0WAVEfmt "), Some("WAV"));
+        assert_eq!(format(b"OggS"), None);
+        assert_eq!(format(b"{\"songs\": []}"), None);
+    }
+
+    #[test]
+    #[cfg(all(feature = "mp3", feature = "wav"))]
+    fn quick_check_files() {
+        assert!(quick_check(Path::new("test_data/test.mp3")).is_ok());
+        assert!(quick_check(Path::new("test_data/nested/silence.wav")).is_ok());
+        assert!(quick_check(Path::new("test_data/empty.playlist")).is_err());
+        assert!(quick_check(Path::new("test_data/not_existing.mp3")).is_err());
+        // Looks like audio by its name, so it is decoded
+        let path = Path::new("test_quick_check.flac");
+        std::fs::write(path, "not audio").unwrap();
+        let result = quick_check(path);
+        std::fs::remove_file(path).unwrap();
+        assert!(result.is_err());
+    }
+
+    ///Counts the bytes read through it
+    #[cfg(feature = "wav")]
+    struct Counting<R>(R, Arc<AtomicU64>);
+
+    #[cfg(feature = "wav")]
+    impl<R: Read> Read for Counting<R> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = self.0.read(buf)?;
+            self.1.fetch_add(n as u64, Ordering::SeqCst);
+            Ok(n)
+        }
+    }
+
+    #[cfg(feature = "wav")]
+    impl<R: Seek> Seek for Counting<R> {
+        fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+            self.0.seek(pos)
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "wav")]
+    fn decoding_streams() {
+        // 20 seconds of stereo CD audio, about 3.4 MiB
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 44100,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut wav = std::io::Cursor::new(Vec::new());
+        let mut writer = hound::WavWriter::new(&mut wav, spec).unwrap();
+        let mut samples = writer.get_i16_writer(44100 * 2 * 20);
+        for i in 0..44100 * 2 * 20 {
+            #[allow(clippy::cast_possible_truncation)]
+            samples.write_sample((i % 512) as i16);
+        }
+        samples.flush().unwrap();
+        writer.finalize().unwrap();
+        wav.set_position(0);
+        let total = wav.get_ref().len() as u64;
+
+        let read = Arc::new(AtomicU64::new(0));
+        let decoder = decode_reader(Counting(wav, Arc::clone(&read))).unwrap();
+        // Play the first second, as much as that is 176 KiB of the file
+        assert_eq!(decoder.take(44100 * 2).count(), 44100 * 2);
+        let read = read.load(Ordering::SeqCst);
+        assert!(read < 1024 * 1024, "Read {read} of {total} bytes");
+    }
+
+    #[test]
+    fn drain_released_output() {
+        let (sink, queue) = Sink::new_idle();
+        let output = SharedOutput(Arc::new(Mutex::new(queue)));
+        sink.append(rodio::source::SineWave::new(440.0).take_duration(Duration::from_secs(2)));
+        output.drain(Duration::from_millis(500));
+        let pos = sink.get_pos();
+        assert!(pos > Duration::from_millis(400) && pos <= Duration::from_millis(500));
+    }
 }
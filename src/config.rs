@@ -1,5 +1,6 @@
 use std::fmt;
 use std::fmt::Formatter;
+use std::num::NonZeroUsize;
 
 use clap::builder::PossibleValue;
 use clap::{Args, Parser, Subcommand, ValueEnum};
@@ -11,6 +12,12 @@ use serde::{Deserialize, Serialize};
 pub struct Cli {
     #[command(subcommand)]
     pub command: Command,
+    #[arg(short, long, global = true)]
+    /// On errors, also print the details of every error that caused them
+    pub verbose: bool,
+    #[arg(short, long, global = true)]
+    /// Show only errors while playing, on stderr. With --no-controls playback is silent.
+    pub quiet: bool,
 }
 
 #[derive(Subcommand)]
@@ -20,44 +27,282 @@ pub enum Command {
     /// Edit or create a playlist
     Edit(EditCommand),
     Display(DisplayCommand),
+    /// Export a playlist as extended M3U
+    Export(ExportCommand),
+    /// List audio output devices
+    Devices,
+    /// List supported audio formats
+    Formats,
 }
 
-#[derive(Args)]
+#[derive(Args, Default)]
+#[allow(clippy::struct_excessive_bools)]
 pub struct PlayCommand {
-    /// Sound file or directory of sound files
+    /// Sound file, directory of sound files or glob pattern.
+    /// Use - to read the paths of sound files from stdin, one per line.
     pub file: String,
     #[arg(short, long)]
     /// Given file is a single playlist
     pub playlist: bool,
+    #[arg(short, long)]
+    /// Include sound files in subdirectories
+    pub recursive: bool,
     #[arg(long)]
-    /// Play songs in a loop
+    /// Don't skip files in directories that are not sound files
+    pub no_filter: bool,
+    #[arg(long)]
+    /// Play songs in a loop. Overwrites playlist config.
     pub repeat: bool,
+    #[arg(long, conflicts_with = "repeat")]
+    /// Play songs only once, even if the playlist is set to repeat
+    pub no_repeat: bool,
+    #[arg(long)]
+    /// Play the same song in a loop until skipped
+    pub repeat_one: bool,
+    #[arg(long, value_name = "SECONDS")]
+    /// Play only the first SECONDS of every song, then go on to the next one.
+    /// Counted from the start a song is trimmed to. Doesn't change the playlist.
+    pub preview: Option<f32>,
+    #[arg(long, value_name = "N")]
+    /// Play only the first N songs, a random sample of them when shuffling.
+    /// With --repeat the same songs play again.
+    pub limit: Option<NonZeroUsize>,
+    #[arg(long, requires = "playlist")]
+    /// Save changes made during playback to the playlist on exit
+    pub autosave: bool,
+    #[arg(long, requires = "playlist")]
+    /// Continue where playback was quit last time, and remember where it is quit this time
+    pub resume: bool,
+    #[arg(long, value_name = "INDEX", conflicts_with = "resume")]
+    /// Start with the song at this index. In order the songs before it are skipped,
+    /// in random modes the rest is played in random order after it.
+    pub start: Option<usize>,
+    #[arg(long, value_name = "TEXT", conflicts_with_all = ["resume", "start"])]
+    /// Like --start, with the first song whose name contains TEXT, ignoring case
+    pub start_name: Option<String>,
     #[arg(long)]
-    /// Overwrites playlist config
+    /// Overwrites playlist config. Between 0 and 5.
     pub volume: Option<f32>,
+    #[arg(
+        long,
+        value_name = "DB",
+        conflicts_with = "volume",
+        allow_negative_numbers = true
+    )]
+    /// Like --volume in decibels, -6 about halves the volume. At most 14.
+    pub volume_db: Option<f32>,
+    #[arg(long, value_enum)]
+    /// Overwrites playlist config. With --repeat, 'on' and 'weighted' pick every song at random.
+    pub random: Option<RandomMode>,
+    #[arg(long, value_name = "MINUTES")]
+    /// Stop playback after this many minutes, fading out over the last seconds
+    pub sleep: Option<f32>,
+    #[arg(long)]
+    /// Limit for the combined song and playlist volume, 3 by default.
+    /// The volume keys don't go above it either.
+    pub max_volume: Option<f32>,
+    #[arg(long, value_name = "STEP")]
+    /// How much the volume keys change the volume of a song: 0.1 takes away 10% per step down
+    /// and adds it back per step up, or adds 0.1 per step with --linear-volume
+    pub volume_step: Option<f32>,
+    #[arg(long)]
+    /// Change the volume in steps of the same size instead of by a share of the volume
+    pub linear_volume: bool,
+    #[arg(long, value_name = "SECONDS")]
+    /// Release the audio device for other programs after being paused this long.
+    /// It is opened again once playback continues.
+    pub release_device: Option<f32>,
+    #[arg(long, value_name = "STARS")]
+    /// Only play songs rated at least this, from 0 to 5. Unrated songs count as 0.
+    pub min_rating: Option<u8>,
+    #[arg(long, value_name = "FILE")]
+    /// JSON file that maps actions to keys, like `{"next": "n", "volume_up": ["up", "+"]}`.
+    /// Actions left out keep their default keys, see the help while playing.
+    pub keymap: Option<String>,
+    #[arg(long)]
+    /// Play straight through without keyboard controls. Implied if stdin is no terminal.
+    pub no_controls: bool,
+    #[arg(long)]
+    /// Print one JSON object per event on stdout, for other programs to follow playback.
+    /// Implies --no-controls.
+    pub json: bool,
+    #[arg(long, conflicts_with = "no_controls")]
+    /// Read commands line by line from stdin instead of keys, even if stdin is a terminal:
+    /// pause, play, next, previous, mute, volume VOLUME, goto INDEX, queue INDEX, bookmark,
+    /// jump [N], sleep [MINUTES], quit.
+    /// Playback continues once stdin is closed.
+    pub commands: bool,
+    #[arg(long, value_name = "PATH")]
+    /// Listen on a Unix socket for other programs to control playback.
+    /// Takes the same commands as --commands, plus status, and sends every event like --json.
+    pub socket: Option<String>,
+    #[arg(long, conflicts_with_all = ["repeat", "repeat_one", "resume", "device", "commands"])]
+    /// Decode every song as fast as possible without an audio device. Fails if a song can't be played.
+    pub validate_only: bool,
+    #[arg(long)]
+    /// Fail if the file of any song is missing, instead of skipping those songs
+    pub strict: bool,
+    #[arg(long, value_name = "NAME")]
+    /// Audio output device to play on, see the devices command
+    pub device: Option<String>,
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["repeat", "repeat_one", "resume", "device", "commands", "validate_only", "json", "sleep", "socket"])]
+    /// Write the songs one after another into a WAV file instead of playing them,
+    /// in the stored order and with their settings. Crossfades are left out.
+    pub output: Option<String>,
 }
 
-#[derive(Args)]
+#[derive(Args, Default)]
+#[allow(clippy::struct_excessive_bools)]
 pub struct EditCommand {
     /// Playlist to edit. Will create a new one if not existing.
+    /// Stored as YAML if it ends in .yaml or .yml, comments in it are lost on saving.
     pub playlist: String,
     #[arg(long)]
-    /// Sound file or directory of sound files to add to playlist.
+    /// Sound file, directory of sound files or glob pattern like "music/**/*.flac" to add to playlist.
     pub file: Option<String>,
+    #[arg(short, long)]
+    /// Include sound files in subdirectories when adding a directory.
+    pub recursive: bool,
+    #[arg(long)]
+    /// Don't skip files that are not sound files when adding a directory.
+    pub no_filter: bool,
+    #[arg(long)]
+    /// M3U playlist to add songs from.
+    pub import: Option<String>,
+    #[arg(long)]
+    /// Store the absolute paths of added songs, so the playlist works from any directory.
+    /// Otherwise paths are stored relative to the playlist. Songs added before keep their paths.
+    pub absolute: bool,
+    #[arg(long, value_name = "PLAYLIST")]
+    /// Playlist to add songs from.
+    pub merge: Option<String>,
+    #[arg(long, requires = "merge")]
+    /// Take the settings of the merged playlist instead of keeping the current ones.
+    pub merge_config: bool,
     #[arg(long)]
-    /// Acts multiplicative to the volume of each song.
+    /// Remove all songs but keep the settings. Applied before any other change.
+    pub clear: bool,
+    #[arg(long)]
+    /// Index of a song to remove from the playlist. Applied before adding files.
+    pub remove: Option<usize>,
+    #[arg(long = "move", num_args = 2, value_names = ["FROM", "TO"])]
+    /// Move the song at index FROM to index TO. Applied before adding files.
+    pub move_song: Option<Vec<usize>>,
+    #[arg(long, value_enum, value_name = "FIELD")]
+    /// Sort the songs by displayed name, full path, full path with numbers in order
+    /// or how often they were played.
+    /// Applied after adding files.
+    pub sort: Option<SortKey>,
+    #[arg(long)]
+    /// Shuffle the stored song order once. Applied after adding files.
+    pub shuffle_now: bool,
+    #[arg(long, value_name = "INDEX")]
+    /// Song to apply --start and --end to. Applied after adding files.
+    pub song: Option<usize>,
+    #[arg(long, value_name = "SECONDS", requires = "song")]
+    /// Skip the beginning of the song. 0 plays it from the beginning.
+    pub start: Option<f32>,
+    #[arg(long, value_name = "SECONDS", requires = "song")]
+    /// Stop the song early. 0 plays it to the end.
+    pub end: Option<f32>,
+    #[arg(long)]
+    /// Acts multiplicative to the volume of each song. Between 0 and 5.
     pub volume: Option<f32>,
+    #[arg(
+        long,
+        value_name = "DB",
+        conflicts_with = "volume",
+        allow_negative_numbers = true
+    )]
+    /// Like --volume in decibels, -6 about halves the volume. At most 14.
+    pub volume_db: Option<f32>,
     #[arg(long, value_enum)]
-    /// Unless songs are repeating 'on' and 'shuffle' act the same.
+    /// Unless songs are repeating 'on', 'weighted' and 'shuffle' act the same.
+    /// 'weighted' prefers songs that were played less often.
     pub random: Option<RandomMode>,
+    #[arg(long, value_name = "SECONDS")]
+    /// Fade songs in and out. 0 disables fading.
+    pub fade: Option<f32>,
+    #[arg(long, value_name = "SECONDS")]
+    /// Overlap consecutive songs, limited to the length of the shorter song. 0 disables it.
+    pub crossfade: Option<f32>,
+    #[arg(long, value_name = "SECONDS")]
+    /// Silence between consecutive songs. Ignored while crossfading. 0 plays songs gapless.
+    pub gap: Option<f32>,
+    #[arg(long, value_name = "FACTOR")]
+    /// Play faster or slower without changing the pitch. 1 disables it.
+    /// Only works if rplaylist was built with the tempo feature.
+    pub tempo: Option<f32>,
+    #[arg(long, value_name = "BOOL")]
+    /// Softly compress peaks of songs amplified above 100% instead of letting them clip.
+    /// Costs some CPU while amplifying.
+    pub limiter: Option<bool>,
+    #[arg(long, value_name = "BOOL")]
+    /// Play the playlist in a loop by default, like play --repeat.
+    pub repeat: Option<bool>,
+    #[arg(long)]
+    /// Level the loudness of all songs by setting their volume. Overwrites the volume of each song.
+    pub analyze_loudness: bool,
+    #[arg(long, value_name = "STARS")]
+    /// Remove songs rated less than this, from 0 to 5. Unrated songs count as 0.
+    /// Songs are rated while playing.
+    pub min_rating: Option<u8>,
     #[arg(long)]
-    /// Remove invalid songs.
+    /// Remove songs whose files are missing or don't start like a supported audio format
     pub validate: bool,
+    #[arg(long, requires = "validate")]
+    /// Validate by opening every song with the decoder instead, which is slower.
+    /// Also stores the duration of valid songs, like --scan.
+    pub thorough: bool,
+    #[arg(long, value_name = "N", requires = "validate")]
+    /// How many songs to validate at once, the number of CPUs by default
+    pub jobs: Option<NonZeroUsize>,
+    #[arg(long)]
+    /// Decode every song once to store its duration.
+    pub scan: bool,
+    #[arg(long)]
+    /// Print the edited playlist instead of saving it.
+    pub dry_run: bool,
+    #[arg(long)]
+    /// Don't ask before removing all songs or replacing a file that is no valid playlist.
+    /// Without a terminal to ask in, these fail unless forced.
+    pub force: bool,
+    #[arg(long, conflicts_with_all = [
+        "file", "import", "merge", "clear", "remove", "move_song", "sort", "shuffle_now", "song",
+        "volume", "volume_db", "random", "fade", "crossfade", "gap", "tempo", "limiter", "repeat",
+        "analyze_loudness", "min_rating", "validate", "scan", "dry_run",
+    ])]
+    /// Restore the playlist as it was before the last edit. Undoing again restores the edit.
+    pub undo: bool,
 }
 
 #[derive(Args)]
 pub struct DisplayCommand {
     pub playlist: String,
+    #[arg(long)]
+    /// Show the duration of each song and the total.
+    /// Slow, as songs without a duration stored by edit --scan have to be decoded.
+    pub with_duration: bool,
+    #[arg(long, value_name = "TEXT")]
+    /// Only show songs whose name, title or artist contains TEXT, ignoring case
+    pub filter: Option<String>,
+    #[arg(long)]
+    /// Summarize the whole playlist at the end. Only counts durations stored by edit --scan.
+    pub stats: bool,
+    #[arg(long, value_enum, value_name = "FIELD")]
+    /// Show the songs sorted, without changing the playlist. Sorting by plays shows how often
+    /// and when each song played to its end.
+    pub sort: Option<SortKey>,
+}
+
+#[derive(Args)]
+pub struct ExportCommand {
+    pub playlist: String,
+    /// M3U file to write
+    pub output: String,
+    #[arg(long)]
+    /// Write song paths relative to this directory instead of absolute paths
+    pub base: Option<String>,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -66,11 +311,18 @@ pub enum RandomMode {
     Off,
     True,
     Shuffle,
+    ///Like `True`, but songs that were played less often are more likely
+    Weighted,
 }
 
 impl ValueEnum for RandomMode {
     fn value_variants<'a>() -> &'a [Self] {
-        &[RandomMode::Off, RandomMode::True, RandomMode::Shuffle]
+        &[
+            RandomMode::Off,
+            RandomMode::True,
+            RandomMode::Shuffle,
+            RandomMode::Weighted,
+        ]
     }
 
     fn to_possible_value(&self) -> Option<PossibleValue> {
@@ -78,6 +330,7 @@ impl ValueEnum for RandomMode {
             RandomMode::Off => "off",
             RandomMode::True => "on",
             RandomMode::Shuffle => "shuffle",
+            RandomMode::Weighted => "weighted",
         }))
     }
 }
@@ -88,6 +341,20 @@ impl fmt::Display for RandomMode {
             RandomMode::Off => write!(f, "OFF"),
             RandomMode::True => write!(f, "TRUE"),
             RandomMode::Shuffle => write!(f, "SHUFFLE"),
+            RandomMode::Weighted => write!(f, "WEIGHTED"),
         }
     }
 }
+
+///What to sort songs by
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum SortKey {
+    ///Name as the song is displayed, from its tags or the file name
+    Name,
+    ///Full path
+    Path,
+    ///Full path, numbers by their value so "9.mp3" comes before "10.mp3"
+    Natural,
+    ///Most played first, see `Song::play_count`
+    Plays,
+}
@@ -1,32 +1,117 @@
+use std::collections::{HashSet, VecDeque};
 use std::error::Error;
+use std::io::Write;
+use std::panic;
 use std::path::PathBuf;
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 use std::{io, thread};
 
-use crossterm::cursor::MoveToColumn;
+use crossterm::cursor::{MoveToColumn, MoveToPreviousLine};
 use crossterm::event::{read, Event, KeyCode, KeyEvent};
-use crossterm::style::{Color, ResetColor, SetForegroundColor};
+use crossterm::style::Stylize;
 use crossterm::terminal::ClearType;
 use crossterm::{style::Print, terminal, ExecutableCommand};
-use rodio::Sink;
+use rand::seq::SliceRandom;
 
-use crate::playlist::Playlist;
-use crate::{audio, file};
+use crate::audio::AudioSink;
+use crate::config::RandomMode;
+use crate::file::{Bookmark, ResumePoint};
+use crate::keymap::{Action, Keymap};
+use crate::playlist::{format_duration, Playlist, MAX_RATING};
+use crate::status;
+use crate::{audio, check_volume, file, parse_minutes, LibError};
 
 pub enum ControlMessage {
     StreamDone,
     StartSong(usize),
+    ///Total duration of the current song, once it is known
+    SongDuration(Option<Duration>),
     InputEvent(Event),
     StreamError(String),
 }
 
+///Where playback is controlled from
+#[derive(Clone, Copy, PartialEq)]
+pub enum Input {
+    ///Keys pressed in the terminal, which also shows progress
+    Keys,
+    ///Text commands read line by line from stdin, see `parse_command`
+    Commands,
+    ///Nothing, playback goes straight through
+    Off,
+}
+
+///The loudest a song is allowed to play unless configured otherwise
+pub const DEFAULT_MAX_VOLUME: f32 = 3.0;
+
+#[allow(clippy::struct_excessive_bools)]
 pub struct Playback {
     pub save_path: Option<PathBuf>,
     pub playlist: Playlist,
     stopping: bool,
     pub control_error: bool,
+    ///Position of the current song in the play order
+    pub position: usize,
+    ///Index of the current song in the playlist, once one started
+    pub song: Option<usize>,
+    ///Play the playlist in a loop
+    pub repeat: bool,
+    ///Songs that could not be opened or decoded
+    pub failed_songs: usize,
+    ///Position to continue at once the current song stops, instead of the next one
+    pub jump: Option<usize>,
+    ///Like `jump`, but the index of a song in the playlist
+    pub goto: Option<usize>,
+    ///Indices of songs to play next, before continuing with the order.
+    ///They are removed once they start playing.
+    pub queue: VecDeque<usize>,
+    ///Replay the current song once it ends
+    pub repeat_one: bool,
+    ///Silence the sink, volume changes only take effect once unmuted
+    pub muted: bool,
+    ///Where to store the current song and position when quitting
+    pub resume_path: Option<PathBuf>,
+    ///Where to start playing, taken once playback starts
+    pub resume: Option<ResumePoint>,
+    ///Limit for the combined song and playlist volume, amplifying more would distort badly
+    pub max_volume: f32,
+    ///Amplification left to the limiter, if the playlist uses it
+    pub boost: audio::Boost,
+    pub volume_steps: VolumeSteps,
+    ///Report events as JSON on stdout, see `status::Event`
+    pub json: bool,
+    ///The controls show only errors, on stderr
+    pub quiet: bool,
+    pub bookmarks: Vec<Bookmark>,
+    ///Where bookmarks are saved as soon as they are added, unless playing without a playlist
+    pub bookmarks_path: Option<PathBuf>,
+    ///Position to start the song at that is jumped to with `goto`
+    pub seek: Option<Duration>,
+    ///When the sleep timer stops playback
+    pub sleep_at: Option<Instant>,
+    ///Indices of songs left out of every play order, as their files are missing,
+    ///they are rated too low or are beyond the limit
+    pub skipped: HashSet<usize>,
+    ///Play only this many songs of the first order, see `player::limit_order`
+    pub limit: Option<usize>,
+    ///Play only the beginning of every song, see `player::previewed`
+    pub preview: Option<Duration>,
+    ///Song that follows the current one in the play order, none once the order ends with it
+    pub next: Option<usize>,
+    ///Let go of the audio device after being paused this long
+    pub release_after: Option<Duration>,
+    pub keymap: Keymap,
+    ///Section of the current song to repeat, in positions of the sink. Only loops once both are set.
+    pub loop_start: Option<Duration>,
+    pub loop_end: Option<Duration>,
+    ///Get every event, see `report`
+    pub listeners: status::Listeners,
+    ///Where to listen for commands, see `socket::listen`
+    pub socket: Option<PathBuf>,
 }
 
 impl Playback {
@@ -36,38 +121,111 @@ impl Playback {
             playlist,
             stopping: false,
             control_error: false,
+            position: 0,
+            song: None,
+            repeat: false,
+            failed_songs: 0,
+            jump: None,
+            goto: None,
+            queue: VecDeque::new(),
+            repeat_one: false,
+            muted: false,
+            resume_path: None,
+            resume: None,
+            max_volume: DEFAULT_MAX_VOLUME,
+            boost: audio::Boost::new(),
+            volume_steps: VolumeSteps::default(),
+            json: false,
+            quiet: false,
+            bookmarks: Vec::new(),
+            bookmarks_path: None,
+            seek: None,
+            sleep_at: None,
+            skipped: HashSet::new(),
+            limit: None,
+            preview: None,
+            next: None,
+            release_after: None,
+            keymap: Keymap::default(),
+            loop_start: None,
+            loop_end: None,
+            listeners: status::Listeners::default(),
+            socket: None,
         }
     }
     pub fn stopped(&self) -> bool {
         self.stopping
     }
+
+    ///Send `event` to all listeners, and print it in JSON mode
+    pub fn report(&self, event: &status::Event) {
+        if self.json {
+            status::emit(event);
+        }
+        self.listeners.send(event);
+    }
+
+    ///Name of the song that plays after the current one, if there is one.
+    ///Once a random order ends, the next one isn't picked yet.
+    fn next_song_name(&self) -> Option<String> {
+        let next = match self.queue.front().or(self.next.as_ref()) {
+            Some(&i) => Some(i),
+            None if !self.repeat => return None,
+            None if self.playlist.config.random == RandomMode::Off => {
+                (0..self.playlist.song_count()).find(|i| !self.skipped.contains(i))
+            }
+            None => return Some(String::from("(random)")),
+        };
+        next.map(|i| self.playlist.song(i).unwrap().to_string())
+    }
 }
 
 struct ControlState {
-    sink: Arc<Sink>,
+    sink: Arc<dyn AudioSink>,
+    ///Where the controls are displayed, the terminal while playing
+    out: Box<dyn Write + Send>,
+    ///Where errors go instead when quiet
+    err: Box<dyn Write + Send>,
+    ///Show nothing but errors
+    quiet: bool,
     last_out_was_action: bool,
+    ///When the last action was displayed, progress won't overwrite it right away
+    last_action: Instant,
     song_index: usize,
+    song_duration: Option<Duration>,
+    ///Last displayed line, to redraw it
+    line: String,
+    ///Digits typed so far, the index of a song to go to
+    goto: String,
 }
 
 impl ControlState {
-    fn new(sink: &Arc<Sink>) -> Self {
+    fn new(sink: &Arc<dyn AudioSink>) -> Self {
         Self {
             sink: Arc::clone(sink),
+            out: Box::new(io::stdout()),
+            err: Box::new(io::stderr()),
+            quiet: false,
             last_out_was_action: false,
+            last_action: Instant::now(),
             song_index: 0,
+            song_duration: None,
+            line: String::new(),
+            goto: String::new(),
         }
     }
 }
 
 pub fn start(
-    sink: &Arc<Sink>, playback: &Arc<Mutex<Playback>>,
+    sink: &Arc<dyn AudioSink>, playback: &Arc<Mutex<Playback>>,
 ) -> (JoinHandle<()>, Sender<ControlMessage>) {
     let playback2 = playback.clone();
     let (tx, rx) = mpsc::channel();
 
-    let state = ControlState::new(sink);
+    let mut state = ControlState::new(sink);
+    state.quiet = playback.lock().unwrap().quiet;
     let handle = thread::spawn(move || {
-        run(state, &playback2, rx);
+        run(state, &playback2, &rx);
     });
 
     let sink2 = sink.clone();
@@ -75,14 +233,343 @@ pub fn start(
     let tx2 = tx.clone();
     thread::spawn(move || {
         read_keys(&tx2);
-        abort_playback(&sink2, &playback2);
+        abort_playback(&*sink2, &playback2);
     });
 
     (handle, tx)
 }
 
+///Only reports errors, without taking over the terminal.
+///In JSON mode, every event is reported on stdout instead.
+pub fn start_headless(playback: &Arc<Mutex<Playback>>) -> (JoinHandle<()>, Sender<ControlMessage>) {
+    let playback = playback.clone();
+    let (tx, rx) = mpsc::channel();
+    let handle = thread::spawn(move || {
+        let mut song_index = 0;
+        for message in rx {
+            let playback = playback.lock().unwrap();
+            match message {
+                ControlMessage::StreamDone => {
+                    report_done(&playback);
+                    break;
+                }
+                ControlMessage::StartSong(index) => {
+                    song_index = index;
+                    report_song_start(&playback, index);
+                }
+                ControlMessage::StreamError(e) => {
+                    report_error(&playback, song_index, &e);
+                    if !playback.json {
+                        eprintln!("{}: {e}", playback.playlist.song(song_index).unwrap());
+                    }
+                }
+                _ => (),
+            }
+        }
+    });
+    (handle, tx)
+}
+
+fn report_song_start(playback: &Playback, index: usize) {
+    let song = playback.playlist.song(index).unwrap();
+    playback.report(&status::Event::SongStart {
+        index,
+        path: song.path.display().to_string(),
+        name: song.to_string(),
+    });
+}
+
+fn report_error(playback: &Playback, index: usize, message: &str) {
+    playback.report(&status::Event::Error { index, message });
+}
+
+fn report_done(playback: &Playback) {
+    playback.report(&status::Event::Done {
+        stopped: playback.stopped(),
+        failed_songs: playback.failed_songs,
+    });
+}
+
+///Read commands from stdin while playing, on top of the headless controls.
+///Once stdin is closed, playback just continues.
+pub fn start_commands(sink: &Arc<dyn AudioSink>, playback: &Arc<Mutex<Playback>>) {
+    let sink = sink.clone();
+    let playback = playback.clone();
+    // Not joined, it blocks on stdin until the program exits
+    thread::spawn(move || {
+        for line in io::stdin().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            if let Err(e) = run_command(&*sink, &playback, &line) {
+                eprintln!("{e}");
+            }
+        }
+    });
+}
+
+///Parse and apply one line of a command, see `parse_command`
+pub fn run_command(
+    sink: &dyn AudioSink, playback: &Mutex<Playback>, line: &str,
+) -> Result<(), String> {
+    parse_command(line).and_then(|c| apply_command(sink, playback, c))
+}
+
+///A command read from stdin
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TextCommand {
+    Pause,
+    Play,
+    Next,
+    Previous,
+    Mute,
+    Volume(f32),
+    Goto(usize),
+    Queue(usize),
+    Bookmark,
+    Jump(Option<usize>),
+    Sleep(Option<Duration>),
+    Quit,
+}
+
+///Commands are `pause`, `play`, `next`, `previous`, `mute`, `volume VOLUME`, `goto INDEX`,
+///`queue INDEX`, `bookmark`, `jump [NUMBER]`, `sleep [MINUTES]` and `quit`.
+///`volume` sets the playlist volume, like `edit --volume`. `jump` goes to a bookmark, the last one by default.
+///`sleep` without minutes or with 0 cancels the sleep timer, minutes may be fractional like `--sleep`.
+fn parse_command(line: &str) -> Result<TextCommand, String> {
+    let mut words = line.split_whitespace();
+    let command = words.next().unwrap_or_default();
+    let argument = words.next();
+    if words.next().is_some() {
+        return Err(format!("Too many arguments: {line}"));
+    }
+    let number = |name| argument.ok_or_else(|| format!("{command} needs {name}"));
+    let command = match (command, argument) {
+        ("pause", None) => TextCommand::Pause,
+        ("play", None) => TextCommand::Play,
+        ("next", None) => TextCommand::Next,
+        ("previous", None) => TextCommand::Previous,
+        ("mute", None) => TextCommand::Mute,
+        ("bookmark", None) => TextCommand::Bookmark,
+        ("jump", None) => TextCommand::Jump(None),
+        ("sleep", None) => TextCommand::Sleep(None),
+        ("sleep", Some(m)) => {
+            let minutes = m.parse().map_err(|e| format!("Invalid minutes: {e}"))?;
+            TextCommand::Sleep(parse_minutes(minutes).map_err(|e| e.to_string())?)
+        }
+        ("jump", Some(n)) => {
+            TextCommand::Jump(Some(n.parse().map_err(|e| format!("Invalid number: {e}"))?))
+        }
+        ("quit", None) => TextCommand::Quit,
+        ("volume", _) => TextCommand::Volume(
+            number("a volume")?
+                .parse()
+                .map_err(|e| format!("Invalid volume: {e}"))?,
+        ),
+        ("goto", _) => TextCommand::Goto(
+            number("an index")?
+                .parse()
+                .map_err(|e| format!("Invalid index: {e}"))?,
+        ),
+        ("queue", _) => TextCommand::Queue(
+            number("an index")?
+                .parse()
+                .map_err(|e| format!("Invalid index: {e}"))?,
+        ),
+        ("pause" | "play" | "next" | "previous" | "mute" | "bookmark" | "quit", Some(_)) => {
+            return Err(format!("{command} takes no argument"));
+        }
+        _ => return Err(format!("Unknown command: {line}")),
+    };
+    Ok(command)
+}
+
+fn apply_command(
+    sink: &dyn AudioSink, playback: &Mutex<Playback>, command: TextCommand,
+) -> Result<(), String> {
+    match command {
+        TextCommand::Pause => sink.pause(),
+        TextCommand::Play => sink.play(),
+        TextCommand::Next => next_song(sink, playback),
+        TextCommand::Previous => previous_song(sink, playback),
+        TextCommand::Mute => {
+            let mut playback = playback.lock().unwrap();
+            playback.muted = !playback.muted;
+            configure_current(sink, &playback);
+        }
+        TextCommand::Volume(v) => {
+            set_volume(sink, &mut playback.lock().unwrap(), v).map_err(|e| e.to_string())?;
+        }
+        TextCommand::Goto(i) => go_to(sink, playback, i)?,
+        TextCommand::Queue(i) => queue_song(&mut playback.lock().unwrap(), i)?,
+        TextCommand::Bookmark => {
+            bookmark(sink, &mut playback.lock().unwrap()).map_err(|e| e.to_string())?;
+        }
+        TextCommand::Jump(n) => {
+            jump_to_bookmark(sink, playback, n)?;
+        }
+        TextCommand::Sleep(duration) => {
+            set_sleep_timer(&mut playback.lock().unwrap(), duration);
+        }
+        TextCommand::Quit => {
+            save_resume_point(sink, &playback.lock().unwrap())
+                .map_err(|e| format!("Unable to save resume point: {e}"))?;
+            stop_playback(sink, playback);
+        }
+    }
+    Ok(())
+}
+
+///Volume of the whole playlist, applied to the current song right away
+pub fn set_volume(
+    sink: &dyn AudioSink, playback: &mut Playback, volume: f32,
+) -> Result<(), LibError> {
+    playback.playlist.config.volume = check_volume(volume)?;
+    configure_current(sink, playback);
+    Ok(())
+}
+
+///Apply the volume and speed of the current song to the sink, if one is playing
+fn configure_current(sink: &dyn AudioSink, playback: &Playback) {
+    let Some(limited) = apply_current(sink, playback) else {
+        return;
+    };
+    let song = playback.playlist.song(playback.song.unwrap()).unwrap();
+    let volume = song.config.volume * playback.playlist.config.volume;
+    playback.report(&status::Event::Volume {
+        volume: if playback.muted {
+            0.0
+        } else {
+            volume.min(playback.max_volume)
+        },
+        limited,
+    });
+}
+
+///Like `configure_current`, without reporting the volume. Returns whether it is limited.
+fn apply_current(sink: &dyn AudioSink, playback: &Playback) -> Option<bool> {
+    let song = playback.song.and_then(|i| playback.playlist.song(i))?;
+    Some(audio::config_sink(
+        sink,
+        &song.config,
+        &playback.playlist.config,
+        playback.muted,
+        playback.max_volume,
+        &playback.boost,
+    ))
+}
+
+///Continue with the song at `index` once the current one is cleared
+fn go_to(sink: &dyn AudioSink, playback: &Mutex<Playback>, index: usize) -> Result<(), String> {
+    {
+        let mut playback = playback.lock().unwrap();
+        let count = playback.playlist.song_count();
+        if index >= count {
+            return Err(format!(
+                "No song at index {index}, playlist has {count} songs"
+            ));
+        }
+        playback.goto = Some(index);
+        playback.seek = None;
+    }
+    sink.clear();
+    sink.play();
+    Ok(())
+}
+
+///Any song but the current one and those that are skipped, if there is one
+fn random_song(playback: &Playback) -> Option<usize> {
+    let candidates: Vec<usize> = (0..playback.playlist.song_count())
+        .filter(|i| Some(*i) != playback.song && !playback.skipped.contains(i))
+        .collect();
+    candidates.choose(&mut rand::thread_rng()).copied()
+}
+
+///Play the song at `index` next, after songs queued before
+pub fn queue_song(playback: &mut Playback, index: usize) -> Result<(), String> {
+    let count = playback.playlist.song_count();
+    if index >= count {
+        return Err(format!(
+            "No song at index {index}, playlist has {count} songs"
+        ));
+    }
+    playback.queue.push_back(index);
+    Ok(())
+}
+
+///Playback fades out over this long before the sleep timer stops it
+const SLEEP_FADE: Duration = Duration::from_secs(10);
+
+///Stop playback once `Playback::sleep_at` is reached, fading out before.
+///The timer can be set and cancelled while running. Runs until `done` is set.
+pub fn start_sleep_timer(
+    sink: &Arc<dyn AudioSink>, playback: &Arc<Mutex<Playback>>, done: &Arc<AtomicBool>,
+) -> JoinHandle<()> {
+    let sink = sink.clone();
+    let playback = playback.clone();
+    let done = done.clone();
+    thread::spawn(move || {
+        let mut fading = false;
+        while !done.load(Ordering::Relaxed) {
+            thread::sleep(Duration::from_millis(100));
+            let mut state = playback.lock().unwrap();
+            let left = state
+                .sleep_at
+                .map(|at| at.saturating_duration_since(Instant::now()));
+            match left {
+                Some(left) if left.is_zero() => {
+                    state.sleep_at = None;
+                    // Failing to save is no reason to keep playing
+                    let _ = save_resume_point(&*sink, &state);
+                    drop(state);
+                    stop_playback(&*sink, &playback);
+                    break;
+                }
+                Some(left) if left < SLEEP_FADE => {
+                    // Songs starting reset the volume, so it is applied again every time
+                    apply_current(&*sink, &state);
+                    sink.set_volume(sink.volume() * left.as_secs_f32() / SLEEP_FADE.as_secs_f32());
+                    fading = true;
+                }
+                _ if fading => {
+                    // The timer was cancelled or set later
+                    apply_current(&*sink, &state);
+                    fading = false;
+                }
+                _ => (),
+            }
+        }
+    })
+}
+
+///Set the sleep timer, or cancel it without `duration`. Returns what was done.
+fn set_sleep_timer(playback: &mut Playback, duration: Option<Duration>) -> String {
+    if let Some(duration) = duration {
+        playback.sleep_at = Some(Instant::now() + duration);
+        format!("Sleep in {} min", duration.as_secs_f32() / 60.0)
+    } else {
+        playback.sleep_at = None;
+        String::from("Sleep timer off")
+    }
+}
+
+///Remember the current song and position, if resuming is enabled
+fn save_resume_point(sink: &dyn AudioSink, playback: &Playback) -> Result<(), LibError> {
+    if let (Some(path), Some(index)) = (&playback.resume_path, playback.song) {
+        let point = ResumePoint {
+            index,
+            position: sink.get_pos(),
+        };
+        file::save_resume(&point, path)?;
+    }
+    Ok(())
+}
+
 ///Error occurred, stop program
-fn abort_playback(sink: &Sink, playback: &Mutex<Playback>) {
+fn abort_playback(sink: &dyn AudioSink, playback: &Mutex<Playback>) {
     {
         playback.lock().unwrap().control_error = true;
     }
@@ -90,58 +577,132 @@ fn abort_playback(sink: &Sink, playback: &Mutex<Playback>) {
 }
 
 /// Stop program for whatever reason
-fn stop_playback(sink: &Sink, state: &Mutex<Playback>) {
+pub fn stop_playback(sink: &dyn AudioSink, state: &Mutex<Playback>) {
     let mut playback = state.lock().unwrap();
     playback.stopping = true;
     sink.clear();
 }
 
-fn run(mut state: ControlState, playback: &Mutex<Playback>, rx: Receiver<ControlMessage>) {
-    //setting up stdout and going into raw mode
-    if let Err(e) = terminal::enable_raw_mode() {
-        eprintln!("Error enabling raw mode: {e}");
-        abort_playback(&state.sink, playback);
-        return;
-    }
+fn run(mut state: ControlState, playback: &Mutex<Playback>, rx: &Receiver<ControlMessage>) {
+    let raw_mode = match RawMode::enable() {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("Error enabling raw mode: {e}");
+            abort_playback(&*state.sink, playback);
+            return;
+        }
+    };
 
     let result = control_loop(&mut state, playback, rx);
-
-    terminal::disable_raw_mode().unwrap();
-    io::stdout()
-        .execute(Print("\n"))
-        .unwrap()
-        .execute(MoveToColumn(0))
-        .unwrap();
+    // The error is only readable once the terminal is back to normal
+    drop(raw_mode);
 
     if let Err(e) = result {
-        abort_playback(&state.sink, playback);
+        abort_playback(&*state.sink, playback);
         eprintln!("Unexpected error: {e}");
     }
 }
 
+type PanicHook = Box<dyn Fn(&panic::PanicHookInfo<'_>) + Sync + Send + 'static>;
+
+///Raw mode while it lives. The terminal is restored however the controls end,
+///with the cursor at the start of a new line below the last output.
+///
+///A panic in any thread leaves raw mode before it is reported, so the message is readable
+///and the shell is usable afterwards. Releases abort on panic, which skips the drop otherwise.
+struct RawMode {
+    ///Hook from before, put back when the controls end
+    previous: Option<Arc<PanicHook>>,
+}
+
+impl RawMode {
+    fn enable() -> Result<RawMode, io::Error> {
+        terminal::enable_raw_mode()?;
+        let previous = Arc::new(panic::take_hook());
+        let report = Arc::clone(&previous);
+        panic::set_hook(Box::new(move |info| {
+            let _ = terminal::disable_raw_mode();
+            let _ = io::stdout().execute(Print("\n"));
+            report(info);
+        }));
+        Ok(RawMode {
+            previous: Some(previous),
+        })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        if let Err(e) = terminal::disable_raw_mode() {
+            eprintln!("Error disabling raw mode: {e}");
+        }
+        let _ = io::stdout()
+            .execute(Print("\n"))
+            .and_then(|o| o.execute(MoveToColumn(0)));
+        //The hook can't be changed while panicking
+        if let Some(previous) = self.previous.take().filter(|_| !thread::panicking()) {
+            drop(panic::take_hook());
+            if let Ok(previous) = Arc::try_unwrap(previous) {
+                panic::set_hook(previous);
+            }
+        }
+    }
+}
+
 fn control_loop(
-    state: &mut ControlState, playback: &Mutex<Playback>, rx: Receiver<ControlMessage>,
+    state: &mut ControlState, playback: &Mutex<Playback>, rx: &Receiver<ControlMessage>,
 ) -> Result<(), Box<dyn Error>> {
-    print_help(state)?;
+    print_help(state, &playback.lock().unwrap().keymap)?;
     state.last_out_was_action = false;
 
-    for c in rx {
-        match c {
-            ControlMessage::StreamDone => break,
-            ControlMessage::InputEvent(e) => {
-                if let Event::Key(event) = e {
-                    eval_key(state, playback, event)?;
+    let tick = Duration::from_secs(1);
+    // The end of a loop is checked more often, so it doesn't play on noticeably
+    let loop_tick = Duration::from_millis(50);
+    let mut next_tick = Instant::now() + tick;
+    loop {
+        let mut timeout = next_tick.saturating_duration_since(Instant::now());
+        if playback.lock().unwrap().loop_end.is_some() {
+            timeout = timeout.min(loop_tick);
+        }
+        let c = match rx.recv_timeout(timeout) {
+            Ok(c) => c,
+            Err(RecvTimeoutError::Timeout) => {
+                let playback = playback.lock().unwrap();
+                repeat_loop(state, &playback);
+                if Instant::now() >= next_tick {
+                    next_tick = Instant::now() + tick;
+                    display_progress(state, &playback)?;
                 }
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        match c {
+            ControlMessage::StreamDone => {
+                report_done(&playback.lock().unwrap());
+                break;
             }
+            ControlMessage::InputEvent(e) => match e {
+                Event::Key(event) => eval_key(state, playback, event)?,
+                Event::Resize(..) => redraw(state)?,
+                _ => (),
+            },
             ControlMessage::StartSong(index) => {
-                let playback = playback.lock().unwrap();
+                let mut playback = playback.lock().unwrap();
+                playback.loop_start = None;
+                playback.loop_end = None;
                 state.song_index = index;
-                display_message(
-                    format!("Playing {}", playback.playlist.song(index).unwrap()).as_str(),
-                    state,
-                )?;
+                state.song_duration = None;
+                report_song_start(&playback, index);
+                let mut text = format!("Playing {}", playback.playlist.song(index).unwrap());
+                if volume_limited(&playback) {
+                    text.push_str(&limit_warning(&playback));
+                }
+                display_message(text.as_str(), state)?;
             }
+            ControlMessage::SongDuration(d) => state.song_duration = d,
             ControlMessage::StreamError(e) => {
+                report_error(&playback.lock().unwrap(), state.song_index, &e);
                 display_error(e.as_str(), state)?;
             }
         }
@@ -152,34 +713,126 @@ fn control_loop(
 fn eval_key(
     state: &mut ControlState, playback: &Mutex<Playback>, event: KeyEvent,
 ) -> Result<(), Box<dyn Error>> {
-    match event.code {
-        KeyCode::Char('q') => stop_playback(&state.sink, playback),
-        KeyCode::Char('h') => {
-            print_help(state)?;
+    let action = playback.lock().unwrap().keymap.action(&event);
+    let Some(action) = action else {
+        // Typing numbers can't be remapped, fractions are for the sleep timer
+        match event.code {
+            KeyCode::Char(c) if c.is_ascii_digit() || c == '.' => {
+                state.goto.push(c);
+                display_action(format!("Goto {}", state.goto).as_str(), state)?;
+            }
+            KeyCode::Backspace if !state.goto.is_empty() => {
+                state.goto.pop();
+                display_action(format!("Goto {}", state.goto).as_str(), state)?;
+            }
+            _ => (),
+        }
+        return Ok(());
+    };
+    match action {
+        Action::Quit => {
+            save_resume(state, playback)?;
+            stop_playback(&*state.sink, playback);
         }
-        KeyCode::Char(' ') => toggle_pause(state)?,
-        KeyCode::Up => {
-            adjust_volume(state, &mut playback.lock().unwrap(), true)?;
+        Action::Help => print_help(state, &playback.lock().unwrap().keymap)?,
+        Action::Pause => toggle_pause(state)?,
+        Action::VolumeUp => adjust_volume(state, &mut playback.lock().unwrap(), true)?,
+        Action::VolumeDown => adjust_volume(state, &mut playback.lock().unwrap(), false)?,
+        Action::SeekForward => seek(state, true)?,
+        Action::SeekBack => seek(state, false)?,
+        Action::Next => next_song(&*state.sink, playback),
+        Action::Previous => previous_song(&*state.sink, playback),
+        Action::Save => save(state, playback)?,
+        Action::RepeatSong => toggle_repeat_one(state, playback)?,
+        Action::Mute => toggle_mute(state, &mut playback.lock().unwrap())?,
+        Action::Faster => adjust_speed(state, &mut playback.lock().unwrap(), true)?,
+        Action::Slower => adjust_speed(state, &mut playback.lock().unwrap(), false)?,
+        Action::Goto => goto_song(state, playback)?,
+        Action::Queue => enqueue(state, &mut playback.lock().unwrap())?,
+        Action::Bookmark => add_bookmark(state, &mut playback.lock().unwrap())?,
+        Action::Bookmarks => list_bookmarks(state, &playback.lock().unwrap())?,
+        Action::GotoBookmark => goto_bookmark(state, playback)?,
+        Action::Sleep => {
+            let typed = std::mem::take(&mut state.goto);
+            let duration = typed
+                .parse()
+                .ok()
+                .and_then(|m| parse_minutes(m).ok().flatten());
+            let text = set_sleep_timer(&mut playback.lock().unwrap(), duration);
+            display_action(text.as_str(), state)?;
         }
-        KeyCode::Down => {
-            adjust_volume(state, &mut playback.lock().unwrap(), false)?;
+        Action::LoopStart => {
+            let mut playback = playback.lock().unwrap();
+            let pos = state.sink.get_pos();
+            playback.loop_start = Some(pos);
+            playback.loop_end = None;
+            display_action(
+                format!("Loop from {}", format_duration(pos)).as_str(),
+                state,
+            )?;
         }
-        KeyCode::Right => {
-            state.sink.clear();
-            state.sink.play();
+        Action::LoopEnd => {
+            let text = set_loop_end(&mut playback.lock().unwrap(), state.sink.get_pos());
+            display_action(text.as_str(), state)?;
+        }
+        Action::RandomJump => {
+            let song = random_song(&playback.lock().unwrap());
+            match song {
+                Some(index) => {
+                    // The index exists, so this can't fail
+                    let _ = go_to(&*state.sink, playback, index);
+                    display_action("Random jump", state)?;
+                }
+                None => display_error("No other song to jump to", state)?,
+            }
+        }
+        Action::Rate => {
+            if let Some(text) = rate_current(&mut playback.lock().unwrap()) {
+                display_action(text.as_str(), state)?;
+            }
+        }
+        Action::LoopClear => {
+            let mut playback = playback.lock().unwrap();
+            playback.loop_start = None;
+            playback.loop_end = None;
+            display_action("Loop cleared", state)?;
         }
-        KeyCode::Char('s') => save(state, playback)?,
-        _ => (),
     }
 
     Ok(())
 }
 
-fn print_help(state: &mut ControlState) -> Result<(), io::Error> {
-    display_action(
-        "Exit: q, Help: h, Play/Pause: space, Volume: \u{2191}/\u{2193}, Next: \u{2192}, Save: s",
-        state,
-    )
+fn print_help(state: &mut ControlState, keymap: &Keymap) -> Result<(), io::Error> {
+    let k = |action| keymap.keys(action);
+    let text = format!(
+        "Exit: {}, Help: {}, Play/Pause: {}, Volume: {}/{}, Previous/Next: {}/{}, Seek: {}/{}, Speed: {}/{}, Repeat song: {}, Mute: {}, Save: {}, Go to song: number+{}, Queue song: [number+]{}, Bookmark: {}, Bookmarks: {}, Go to bookmark: [number+]{}, Sleep timer: minutes+{sleep}, cancel with {sleep}, Loop start/end: {}/{}, Clear loop: {}, Random song: {}, Rate song: {}",
+        k(Action::Quit),
+        k(Action::Help),
+        k(Action::Pause),
+        k(Action::VolumeUp),
+        k(Action::VolumeDown),
+        k(Action::Previous),
+        k(Action::Next),
+        k(Action::SeekBack),
+        k(Action::SeekForward),
+        k(Action::Slower),
+        k(Action::Faster),
+        k(Action::RepeatSong),
+        k(Action::Mute),
+        k(Action::Save),
+        k(Action::Goto),
+        k(Action::Queue),
+        k(Action::Bookmark),
+        k(Action::Bookmarks),
+        k(Action::GotoBookmark),
+        k(Action::LoopStart),
+        k(Action::LoopEnd),
+        k(Action::LoopClear),
+        k(Action::RandomJump),
+        k(Action::Rate),
+        sleep = k(Action::Sleep),
+    );
+    display_action(&text, state)
 }
 
 fn toggle_pause(state: &mut ControlState) -> Result<(), io::Error> {
@@ -192,18 +845,224 @@ fn toggle_pause(state: &mut ControlState) -> Result<(), io::Error> {
     }
 }
 
+fn toggle_repeat_one(
+    state: &mut ControlState, playback: &Mutex<Playback>,
+) -> Result<(), io::Error> {
+    let repeat_one = {
+        let mut playback = playback.lock().unwrap();
+        playback.repeat_one = !playback.repeat_one;
+        playback.repeat_one
+    };
+    if repeat_one {
+        display_action("Repeat song: on", state)
+    } else {
+        display_action("Repeat song: off", state)
+    }
+}
+
+fn toggle_mute(state: &mut ControlState, playback: &mut Playback) -> Result<(), io::Error> {
+    playback.muted = !playback.muted;
+    configure_current(&*state.sink, playback);
+    if playback.muted {
+        display_action("Muted", state)
+    } else {
+        let bar = volume_bar(effective_volume(playback));
+        let mut text = format!("Unmuted {bar}");
+        if volume_limited(playback) {
+            text.push_str(&limit_warning(playback));
+        }
+        display_action(text.as_str(), state)
+    }
+}
+
+///Skip to the next song in the play order, even when repeating the current song.
+pub fn next_song(sink: &dyn AudioSink, playback: &Mutex<Playback>) {
+    {
+        let mut playback = playback.lock().unwrap();
+        playback.jump = Some(playback.position + 1);
+    }
+    sink.clear();
+    sink.play();
+}
+
+///Jump to the song whose index was typed
+fn goto_song(state: &mut ControlState, playback: &Mutex<Playback>) -> Result<(), io::Error> {
+    let typed = std::mem::take(&mut state.goto);
+    let Ok(index) = typed.parse::<usize>() else {
+        return Ok(());
+    };
+    match go_to(&*state.sink, playback, index) {
+        Ok(()) => Ok(()),
+        Err(e) => display_error(e.as_str(), state),
+    }
+}
+
+///Queue the song whose index was typed, or the current song if nothing was typed
+fn enqueue(state: &mut ControlState, playback: &mut Playback) -> Result<(), io::Error> {
+    let typed = std::mem::take(&mut state.goto);
+    let Some(index) = typed.parse().ok().or(playback.song) else {
+        return Ok(());
+    };
+    match queue_song(playback, index) {
+        Ok(()) => {
+            let text = format!("Queued {}", playback.playlist.song(index).unwrap());
+            display_action(text.as_str(), state)
+        }
+        Err(e) => display_error(e.as_str(), state),
+    }
+}
+
+///Bookmark the current position
+fn add_bookmark(state: &mut ControlState, playback: &mut Playback) -> Result<(), io::Error> {
+    match bookmark(&*state.sink, playback) {
+        Ok(number) => {
+            let text = format!("Bookmark {number}: {}", playback.bookmarks[number].name);
+            display_action(text.as_str(), state)
+        }
+        Err(e) => display_error(format!("Unable to save bookmark: {e}").as_str(), state),
+    }
+}
+
+fn list_bookmarks(state: &mut ControlState, playback: &Playback) -> Result<(), io::Error> {
+    if playback.bookmarks.is_empty() {
+        return display_action("No bookmarks", state);
+    }
+    let list: Vec<String> = playback
+        .bookmarks
+        .iter()
+        .enumerate()
+        .map(|(i, b)| format!("{i}: {}", b.name))
+        .collect();
+    display_message(format!("Bookmarks {}", list.join(", ")).as_str(), state)
+}
+
+///Jump to the bookmark whose number was typed, or the last one if nothing was typed
+fn goto_bookmark(state: &mut ControlState, playback: &Mutex<Playback>) -> Result<(), io::Error> {
+    let typed = std::mem::take(&mut state.goto);
+    match jump_to_bookmark(&*state.sink, playback, typed.parse().ok()) {
+        Ok(name) => display_action(format!("Bookmark {name}").as_str(), state),
+        Err(e) => display_error(e.as_str(), state),
+    }
+}
+
+///Add a bookmark at the current position, saving all bookmarks if there is a playlist.
+///Returns the number of the new bookmark, it is kept even if saving failed.
+fn bookmark(sink: &dyn AudioSink, playback: &mut Playback) -> Result<usize, LibError> {
+    let Some(index) = playback.song else {
+        return Err(LibError::Playback("No song is playing"));
+    };
+    let position = sink.get_pos();
+    let song = playback.playlist.song(index).unwrap();
+    playback.bookmarks.push(Bookmark {
+        name: format!("{song} {}", format_duration(position)),
+        index,
+        position,
+    });
+    if let Some(path) = &playback.bookmarks_path {
+        file::save_bookmarks(&playback.bookmarks, path)?;
+    }
+    Ok(playback.bookmarks.len() - 1)
+}
+
+///Continue at the bookmark with the given number, or the last one.
+///Returns the name of the bookmark.
+fn jump_to_bookmark(
+    sink: &dyn AudioSink, playback: &Mutex<Playback>, number: Option<usize>,
+) -> Result<String, String> {
+    let mut playback = playback.lock().unwrap();
+    let bookmark = match number {
+        Some(n) => playback.bookmarks.get(n),
+        None => playback.bookmarks.last(),
+    }
+    .cloned()
+    .ok_or("No such bookmark")?;
+    if bookmark.index >= playback.playlist.song_count() {
+        return Err(format!(
+            "The song of bookmark {} is no longer in the playlist",
+            bookmark.name
+        ));
+    }
+    if playback.song == Some(bookmark.index) {
+        drop(playback);
+        sink.try_seek(bookmark.position)
+            .map_err(|e| format!("Unable to seek: {e}"))?;
+    } else {
+        playback.goto = Some(bookmark.index);
+        playback.seek = Some(bookmark.position);
+        drop(playback);
+        sink.clear();
+        sink.play();
+    }
+    Ok(bookmark.name)
+}
+
+///Go back to the previous song in the play order.
+///On the first song of the order, the current song is restarted instead.
+pub fn previous_song(sink: &dyn AudioSink, playback: &Mutex<Playback>) {
+    {
+        let mut playback = playback.lock().unwrap();
+        playback.jump = Some(playback.position.saturating_sub(1));
+    }
+    sink.clear();
+    sink.play();
+}
+
+///Start looping once the end is after the start
+fn set_loop_end(playback: &mut Playback, pos: Duration) -> String {
+    match playback.loop_start {
+        None => String::from("Set the start of the loop first"),
+        Some(start) if pos <= start => String::from("The loop has to end after its start"),
+        Some(start) => {
+            playback.loop_end = Some(pos);
+            format!(
+                "Looping {} - {}",
+                format_duration(start),
+                format_duration(pos)
+            )
+        }
+    }
+}
+
+///Jump back to the start of the loop once its end is passed
+fn repeat_loop(state: &ControlState, playback: &Playback) {
+    if let (Some(start), Some(end)) = (playback.loop_start, playback.loop_end) {
+        if state.sink.get_pos() >= end {
+            // If this fails, the song just plays on
+            let _ = state.sink.try_seek(start);
+        }
+    }
+}
+
+///Not forward means backward
+fn seek(state: &mut ControlState, forward: bool) -> Result<(), io::Error> {
+    let delta = Duration::from_secs(10);
+    let pos = state.sink.get_pos();
+    let pos = if forward {
+        pos + delta
+    } else {
+        pos.saturating_sub(delta)
+    };
+    match state.sink.try_seek(pos) {
+        Ok(()) => display_action(format!("Seek {}", format_duration(pos)).as_str(), state),
+        Err(e) => display_error(format!("Unable to seek: {e}").as_str(), state),
+    }
+}
+
 fn save(state: &mut ControlState, playback: &Mutex<Playback>) -> Result<(), Box<dyn Error>> {
     let playback = playback.lock().unwrap();
     if let Some(path) = &playback.save_path {
         match file::save_playlist(&playback.playlist, path) {
             Err(e) => {
                 display_error(
-                    format!("Unable to save to {path:?}, error: {e}").as_str(),
+                    format!("Unable to save to {}, error: {e}", path.display()).as_str(),
                     state,
                 )?;
             }
-            Ok(_) => {
-                display_action(format!("Successfully saved to {path:?}").as_str(), state)?;
+            Ok(()) => {
+                display_action(
+                    format!("Successfully saved to {}", path.display()).as_str(),
+                    state,
+                )?;
             }
         }
     } else {
@@ -212,74 +1071,297 @@ fn save(state: &mut ControlState, playback: &Mutex<Playback>) -> Result<(), Box<
     Ok(())
 }
 
-///Not up means down
+///Remember the current song and position, if resuming is enabled
+fn save_resume(state: &mut ControlState, playback: &Mutex<Playback>) -> Result<(), io::Error> {
+    let result = save_resume_point(&*state.sink, &playback.lock().unwrap());
+    if let Err(e) = result {
+        display_error(format!("Unable to save resume point: {e}").as_str(), state)?;
+    }
+    Ok(())
+}
+
+///Not up means down. Changes the song that is playing, which may have started
+///before the controls got `StartSong`.
 fn adjust_volume(
     state: &mut ControlState, playback: &mut Playback, up: bool,
 ) -> Result<(), Box<dyn Error>> {
-    let song = playback.playlist.song_mut(state.song_index).unwrap();
-    song.config.volume = calc_new_volume(song.config.volume, up);
-    display_action(
-        format!("Volume {:.0}%", song.config.volume * 100.0).as_str(),
-        state,
-    )?;
-
-    let song = playback.playlist.song(state.song_index).unwrap();
-    audio::config_sink(&state.sink, &song.config, &playback.playlist.config);
+    let Some(index) = playback.song else {
+        return Ok(());
+    };
+    let song = playback.playlist.song_mut(index).unwrap();
+    song.config.volume = playback.volume_steps.next(song.config.volume, up);
+    let mut text = format!("Volume {}", volume_bar(effective_volume(playback)));
+    if playback.muted {
+        text.push_str(" (muted)");
+    }
+    if volume_limited(playback) {
+        text.push_str(&limit_warning(playback));
+    }
+    display_action(text.as_str(), state)?;
+
+    configure_current(&*state.sink, playback);
     Ok(())
 }
 
+///Rate the current song a star more, or unrated after the most stars.
+///Returns what to show, None without a current song.
+fn rate_current(playback: &mut Playback) -> Option<String> {
+    let song = playback.playlist.song_mut(playback.song?).unwrap();
+    song.rating = match song.rating {
+        Some(r) if r >= MAX_RATING => None,
+        Some(r) => Some(r + 1),
+        None => Some(1),
+    };
+    Some(match song.rating {
+        Some(r) => format!("Rating {r}/{MAX_RATING}"),
+        None => String::from("Rating cleared"),
+    })
+}
+
+///Not faster means slower. Changes the pitch as well.
+fn adjust_speed(
+    state: &mut ControlState, playback: &mut Playback, faster: bool,
+) -> Result<(), io::Error> {
+    let Some(index) = playback.song else {
+        return Ok(());
+    };
+    let song = playback.playlist.song_mut(index).unwrap();
+    song.config.speed = calc_new_speed(song.config.speed, faster);
+    let text = format!("Speed {:.2}x", song.config.speed);
+
+    apply_current(&*state.sink, playback);
+    display_action(text.as_str(), state)
+}
+
 ///Won't be overwritten
 fn display_message(text: &str, state: &mut ControlState) -> Result<(), io::Error> {
-    let mut stdout = io::stdout();
+    if state.quiet {
+        return Ok(());
+    }
     if state.last_out_was_action {
-        stdout.execute(terminal::Clear(ClearType::CurrentLine))?;
+        clear_line(state)?;
         state.last_out_was_action = false;
     } else {
-        stdout.execute(Print("\n"))?;
+        state.out.execute(Print("\n"))?;
     }
-    stdout.execute(MoveToColumn(0))?.execute(Print(text))?;
+    state.out.execute(MoveToColumn(0))?.execute(Print(text))?;
+    state.line = String::from(text);
+
+    Ok(())
+}
+
+///Clear the last line, including the rows it wrapped into
+fn clear_line(state: &mut ControlState) -> Result<(), io::Error> {
+    let rows = wrapped_rows(&state.line);
+    if rows > 1 {
+        state.out.execute(MoveToPreviousLine(rows - 1))?;
+    }
+    state
+        .out
+        .execute(MoveToColumn(0))?
+        .execute(terminal::Clear(ClearType::FromCursorDown))?;
+    Ok(())
+}
 
+///Print the last line again after the terminal was resized
+fn redraw(state: &mut ControlState) -> Result<(), io::Error> {
+    if state.quiet {
+        return Ok(());
+    }
+    clear_line(state)?;
+    state.out.execute(Print(&state.line))?;
     Ok(())
 }
 
+///Rows `text` takes up at the current width, assuming the terminal rewraps it on resize
+fn wrapped_rows(text: &str) -> u16 {
+    let width = terminal::size().map_or(0, |(w, _)| usize::from(w));
+    let len = visible_len(text);
+    if width == 0 || len == 0 {
+        return 1;
+    }
+    u16::try_from(len.div_ceil(width)).unwrap_or(u16::MAX)
+}
+
+///Length without escape sequences like colors
+fn visible_len(text: &str) -> usize {
+    let mut len = 0;
+    let mut escape = false;
+    for c in text.chars() {
+        if escape {
+            escape = !c.is_ascii_alphabetic();
+        } else if c == '\u{1b}' {
+            escape = true;
+        } else {
+            len += 1;
+        }
+    }
+    len
+}
+
 ///Will be overwritten by next output
 fn display_action(text: &str, state: &mut ControlState) -> Result<(), io::Error> {
     display_message(text, state)?;
     state.last_out_was_action = true;
+    state.last_action = Instant::now();
+    Ok(())
+}
+
+///Elapsed and total time of the current song and the song after it,
+///unless an action was displayed just now
+fn display_progress(state: &mut ControlState, playback: &Playback) -> Result<(), io::Error> {
+    if state.last_action.elapsed() < Duration::from_secs(2) {
+        return Ok(());
+    }
+    let elapsed = format_duration(state.sink.get_pos());
+    let mut text = match state.song_duration {
+        Some(d) => format!("{elapsed} / {}", format_duration(d)),
+        None => elapsed,
+    };
+    if let Some(next) = playback.next_song_name() {
+        text = format!("{text}, Next: {next}");
+    }
+    display_message(text.as_str(), state)?;
+    state.last_out_was_action = true;
     Ok(())
 }
 
 ///Error variant for `display_message`
 fn display_error(text: &str, state: &mut ControlState) -> Result<(), io::Error> {
-    let mut stdout = io::stdout();
-    stdout.execute(SetForegroundColor(Color::DarkRed))?;
-    display_message(text, state)?;
-    stdout.execute(ResetColor)?;
-    Ok(())
+    if state.quiet {
+        // Raw mode doesn't return to the start of the line on its own
+        return write!(state.err, "{text}\r\n");
+    }
+    display_message(text.dark_red().to_string().as_str(), state)
+}
+
+///Volume of the sink, unless muted
+fn effective_volume(playback: &Playback) -> f32 {
+    combined_volume(playback).min(playback.max_volume)
 }
 
-fn calc_new_volume(mut vol: f32, up: bool) -> f32 {
-    let ratio = 0.1;
-    let min_vol = 0.05;
-    let max_vol = 3.0;
-    if up {
-        vol /= 1.0 - ratio;
-        if vol > max_vol {
-            vol = max_vol;
+///Of the song playing right now, not the one the controls last heard of
+fn combined_volume(playback: &Playback) -> f32 {
+    let song = playback.song.and_then(|i| playback.playlist.song(i));
+    song.map_or(1.0, |s| s.config.volume) * playback.playlist.config.volume
+}
+
+fn volume_limited(playback: &Playback) -> bool {
+    combined_volume(playback) > playback.max_volume
+}
+
+fn limit_warning(playback: &Playback) -> String {
+    format!(" (limited to {:.0}%)", playback.max_volume * 100.0)
+        .yellow()
+        .to_string()
+}
+
+///Single line bar with 10% per cell. Amplification above 100% is red as it may distort.
+///Followed by the volume in percent and in decibels, which is closer to how loud it sounds.
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn volume_bar(volume: f32) -> String {
+    let cells = 20;
+    let full = 10;
+    let filled = ((volume * 10.0).round() as usize).min(cells);
+    let normal = filled.min(full);
+    format!(
+        "{}{}{} {:.0}% ({})",
+        "\u{2588}".repeat(normal),
+        "\u{2588}".repeat(filled - normal).red(),
+        "\u{2591}".repeat(cells - filled),
+        volume * 100.0,
+        format_db(volume)
+    )
+}
+
+///Like "-6.0 dB", "silent" for no volume
+fn format_db(volume: f32) -> String {
+    if volume <= 0.0 {
+        return String::from("silent");
+    }
+    let db = audio::volume_to_db(volume);
+    // No "-0.0 dB" for volumes just below 100%
+    if db.abs() < 0.05 {
+        return String::from("0.0 dB");
+    }
+    format!("{db:+.1} dB")
+}
+
+///How the volume keys change the volume of a song
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VolumeSteps {
+    ///Share of the volume a step down takes away, or the volume of a step if linear
+    pub step: f32,
+    pub linear: bool,
+    ///The keys don't go below `min` or above `max`, but leave volumes outside of them
+    ///that were set otherwise, e.g. with `edit`
+    pub min: f32,
+    pub max: f32,
+}
+
+impl Default for VolumeSteps {
+    fn default() -> Self {
+        VolumeSteps {
+            step: 0.1,
+            linear: false,
+            min: 0.05,
+            max: DEFAULT_MAX_VOLUME,
         }
-    } else {
-        vol *= 1.0 - ratio;
-        if vol < min_vol {
-            vol = min_vol;
+    }
+}
+
+impl VolumeSteps {
+    ///A step up undoes a step down, unless one of them was stopped at `min` or `max`
+    fn next(&self, volume: f32, up: bool) -> f32 {
+        if up {
+            if volume >= self.max {
+                return volume;
+            }
+            // Multiplying would leave a silent song silent
+            if volume < self.min {
+                return self.min;
+            }
+            let volume = if self.linear {
+                round_volume(volume + self.step)
+            } else {
+                volume / (1.0 - self.step)
+            };
+            volume.min(self.max)
+        } else {
+            if volume <= self.min {
+                return volume;
+            }
+            let volume = if self.linear {
+                round_volume(volume - self.step)
+            } else {
+                volume * (1.0 - self.step)
+            };
+            volume.max(self.min)
         }
     }
-    vol
+}
+
+///Round to avoid accumulating float errors like 0.70000005
+fn round_volume(volume: f32) -> f32 {
+    (volume * 1000.0).round() / 1000.0
+}
+
+fn calc_new_speed(speed: f32, faster: bool) -> f32 {
+    let step = 0.05;
+    let speed = if faster { speed + step } else { speed - step };
+    // Round to avoid accumulating float errors like 1.2500001
+    ((speed * 100.0).round() / 100.0).clamp(0.25, 4.0)
 }
 
 fn read_keys(rx: &Sender<ControlMessage>) {
     loop {
         match read() {
-            Ok(e) => rx.send(ControlMessage::InputEvent(e)).unwrap(),
+            Ok(e) => {
+                // The controls are gone, so there is nobody left to read keys for
+                if rx.send(ControlMessage::InputEvent(e)).is_err() {
+                    return;
+                }
+            }
             Err(e) => {
                 eprintln!("Error reading input: {e}");
                 return;
@@ -287,3 +1369,313 @@ fn read_keys(rx: &Sender<ControlMessage>) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crossterm::event::KeyModifiers;
+
+    use super::*;
+    use crate::audio::FakeSink;
+    use crate::playlist::Song;
+
+    ///Run the control loop on `messages` as if they were read from the keyboard and the player.
+    ///Returns the sink to check what the controls did with it.
+    fn control(playback: &Mutex<Playback>, messages: Vec<ControlMessage>) -> Arc<FakeSink> {
+        let fake = Arc::new(FakeSink::default());
+        let sink: Arc<dyn AudioSink> = fake.clone();
+        let mut state = ControlState::new(&sink);
+        state.out = Box::new(io::sink());
+        let (tx, rx) = mpsc::channel();
+        for m in messages {
+            tx.send(m).unwrap();
+        }
+        tx.send(ControlMessage::StreamDone).unwrap();
+        control_loop(&mut state, playback, &rx).expect("Controls should not fail");
+        fake
+    }
+
+    fn key(code: KeyCode) -> ControlMessage {
+        ControlMessage::InputEvent(Event::Key(KeyEvent::new(code, KeyModifiers::NONE)))
+    }
+
+    fn two_songs() -> Playlist {
+        let mut p = Playlist::new();
+        p.add_song(Song::new(PathBuf::from("a.mp3"))).unwrap();
+        p.add_song(Song::new(PathBuf::from("b.mp3"))).unwrap();
+        p
+    }
+
+    #[test]
+    fn keys_pause_and_change_volume() {
+        let mut playback = Playback::new(None, two_songs());
+        playback.song = Some(1);
+        let playback = Mutex::new(playback);
+        let sink = control(
+            &playback,
+            vec![
+                ControlMessage::StartSong(1),
+                key(KeyCode::Char(' ')),
+                key(KeyCode::Up),
+                key(KeyCode::Char('j')),
+                key(KeyCode::Char('j')),
+                key(KeyCode::Char(' ')),
+            ],
+        );
+        assert_eq!(sink.count("pause"), 1);
+        assert_eq!(sink.count("play"), 1);
+        assert_eq!(sink.count("set_volume"), 3);
+        let playback = playback.lock().unwrap();
+        let volume = playback.playlist.song(1).unwrap().config.volume;
+        assert!((volume - 0.9).abs() < 1e-5);
+        assert!((sink.volume() - volume).abs() < f32::EPSILON);
+        assert!((playback.playlist.song(0).unwrap().config.volume - 1.0).abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn volume_key_changes_playing_song() {
+        let mut playback = Playback::new(None, two_songs());
+        // The next song already started, before the controls got its StartSong
+        playback.song = Some(1);
+        let playback = Mutex::new(playback);
+        let sink = control(
+            &playback,
+            vec![ControlMessage::StartSong(0), key(KeyCode::Up)],
+        );
+        let playback = playback.lock().unwrap();
+        let volume = playback.playlist.song(1).unwrap().config.volume;
+        assert!(volume > 1.0);
+        assert!((playback.playlist.song(0).unwrap().config.volume - 1.0).abs() < f32::EPSILON);
+        assert!((sink.volume() - volume).abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn quiet_shows_only_errors() {
+        let sink: Arc<dyn AudioSink> = Arc::new(FakeSink::default());
+        let mut state = ControlState::new(&sink);
+        let (out, err) = (status::Buffer::default(), status::Buffer::default());
+        state.out = Box::new(out.clone());
+        state.err = Box::new(err.clone());
+        state.quiet = true;
+        display_action("Pause", &mut state).unwrap();
+        display_message("Playing a.mp3", &mut state).unwrap();
+        display_error("Unable to seek", &mut state).unwrap();
+        redraw(&mut state).unwrap();
+        assert_eq!(out.text(), "");
+        assert_eq!(err.text(), "Unable to seek\r\n");
+    }
+
+    #[test]
+    fn volume_in_db() {
+        assert_eq!(format_db(1.0), "0.0 dB");
+        assert_eq!(format_db(0.5), "-6.0 dB");
+        assert_eq!(format_db(2.0), "+6.0 dB");
+        assert_eq!(format_db(0.0), "silent");
+        assert!(volume_bar(0.5).ends_with(" 50% (-6.0 dB)"));
+    }
+
+    #[test]
+    fn rate_key_cycles() {
+        let mut playback = Playback::new(None, two_songs());
+        playback.song = Some(0);
+        let playback = Mutex::new(playback);
+        control(&playback, (0..2).map(|_| key(KeyCode::Char('*'))).collect());
+        assert_eq!(
+            playback.lock().unwrap().playlist.song(0).unwrap().rating,
+            Some(2)
+        );
+        control(&playback, (0..4).map(|_| key(KeyCode::Char('*'))).collect());
+        let playback = playback.lock().unwrap();
+        assert_eq!(playback.playlist.song(0).unwrap().rating, None);
+        assert_eq!(playback.playlist.song(1).unwrap().rating, None);
+    }
+
+    #[test]
+    fn keys_save_playlist() {
+        let path = PathBuf::from("test_keys_save.playlist");
+        let playback = Mutex::new(Playback::new(Some(path.clone()), two_songs()));
+        control(
+            &playback,
+            vec![
+                ControlMessage::StartSong(0),
+                key(KeyCode::Char('m')),
+                key(KeyCode::Char('s')),
+            ],
+        );
+        let saved = file::load_playlist(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(saved.expect("Save key should save").song_count(), 2);
+        assert!(playback.lock().unwrap().muted);
+    }
+
+    #[test]
+    fn keys_go_to_typed_song() {
+        let playback = Mutex::new(Playback::new(None, two_songs()));
+        let sink = control(
+            &playback,
+            vec![
+                ControlMessage::StartSong(0),
+                key(KeyCode::Char('1')),
+                key(KeyCode::Enter),
+                key(KeyCode::Char('q')),
+            ],
+        );
+        let playback = playback.lock().unwrap();
+        assert_eq!(playback.goto, Some(1));
+        assert!(playback.stopped());
+        assert_eq!(sink.count("clear"), 2);
+    }
+
+    #[test]
+    fn volume_steps_undo_each_other() {
+        for steps in [
+            VolumeSteps::default(),
+            VolumeSteps {
+                step: 0.05,
+                linear: true,
+                ..Default::default()
+            },
+        ] {
+            let mut volume = 0.8;
+            for _ in 0..5 {
+                volume = steps.next(volume, true);
+            }
+            for _ in 0..5 {
+                volume = steps.next(volume, false);
+            }
+            assert!((volume - 0.8).abs() < 1e-4, "{steps:?} ended at {volume}");
+        }
+    }
+
+    #[test]
+    fn volume_steps_at_limits() {
+        let steps = VolumeSteps::default();
+        assert!((steps.next(2.9, true) - steps.max).abs() < f32::EPSILON);
+        assert!((steps.next(steps.max, true) - steps.max).abs() < f32::EPSILON);
+        // Set louder with edit, up must not make it quieter
+        assert!((steps.next(5.0, true) - 5.0).abs() < f32::EPSILON);
+        assert!((steps.next(5.0, false) - 4.5).abs() < f32::EPSILON);
+        assert!((steps.next(0.0, true) - steps.min).abs() < f32::EPSILON);
+        assert!((steps.next(0.052, false) - steps.min).abs() < f32::EPSILON);
+        assert!((steps.next(0.01, false) - 0.01).abs() < f32::EPSILON);
+        let linear = VolumeSteps {
+            linear: true,
+            ..Default::default()
+        };
+        assert!((linear.next(0.1, false) - linear.min).abs() < f32::EPSILON);
+        assert!((linear.next(1.0, true) - 1.1).abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn parse_valid_commands() {
+        assert_eq!(parse_command("pause"), Ok(TextCommand::Pause));
+        assert_eq!(parse_command("  next "), Ok(TextCommand::Next));
+        assert_eq!(parse_command("volume 0.5"), Ok(TextCommand::Volume(0.5)));
+        assert_eq!(parse_command("goto 3"), Ok(TextCommand::Goto(3)));
+        assert_eq!(parse_command("queue 0"), Ok(TextCommand::Queue(0)));
+        assert_eq!(parse_command("jump"), Ok(TextCommand::Jump(None)));
+        assert_eq!(parse_command("jump 2"), Ok(TextCommand::Jump(Some(2))));
+        assert_eq!(
+            parse_command("sleep 30"),
+            Ok(TextCommand::Sleep(Some(Duration::from_mins(30))))
+        );
+        assert_eq!(
+            parse_command("sleep 0.5"),
+            Ok(TextCommand::Sleep(Some(Duration::from_secs(30))))
+        );
+        assert_eq!(parse_command("sleep 0"), Ok(TextCommand::Sleep(None)));
+        assert!(parse_command("sleep -1").is_err());
+    }
+
+    #[test]
+    fn sleep_timer() {
+        let mut playback = Playback::new(None, Playlist::new());
+        set_sleep_timer(&mut playback, Some(Duration::from_mins(5)));
+        let left = playback.sleep_at.unwrap() - Instant::now();
+        assert!(left > Duration::from_secs(299) && left <= Duration::from_mins(5));
+        set_sleep_timer(&mut playback, None);
+        assert_eq!(playback.sleep_at, None);
+    }
+
+    #[test]
+    fn keys_set_fractional_sleep_timer() {
+        let playback = Mutex::new(Playback::new(None, two_songs()));
+        control(
+            &playback,
+            vec![
+                ControlMessage::StartSong(0),
+                key(KeyCode::Char('0')),
+                key(KeyCode::Char('.')),
+                key(KeyCode::Char('5')),
+                key(KeyCode::Char('z')),
+                key(KeyCode::Char('q')),
+            ],
+        );
+        let left = playback.lock().unwrap().sleep_at.unwrap() - Instant::now();
+        assert!(left > Duration::from_secs(29) && left <= Duration::from_secs(30));
+    }
+
+    #[test]
+    fn next_song_name() {
+        let mut playlist = Playlist::new();
+        playlist.add_songs(vec![
+            Song::new(PathBuf::from("a.mp3")),
+            Song::new(PathBuf::from("b.mp3")),
+        ]);
+        let mut playback = Playback::new(None, playlist);
+        assert_eq!(playback.next_song_name(), None);
+        playback.next = Some(1);
+        assert_eq!(playback.next_song_name().as_deref(), Some("b.mp3"));
+        playback.queue.push_back(0);
+        assert_eq!(playback.next_song_name().as_deref(), Some("a.mp3"));
+
+        playback.queue.clear();
+        playback.next = None;
+        playback.repeat = true;
+        playback.skipped.insert(0);
+        assert_eq!(playback.next_song_name().as_deref(), Some("b.mp3"));
+        playback.playlist.config.random = RandomMode::Shuffle;
+        assert_eq!(playback.next_song_name().as_deref(), Some("(random)"));
+    }
+
+    #[test]
+    fn random_song_skips_current() {
+        let mut playlist = Playlist::new();
+        playlist.add_songs(vec![
+            Song::new(PathBuf::from("a.mp3")),
+            Song::new(PathBuf::from("b.mp3")),
+            Song::new(PathBuf::from("c.mp3")),
+        ]);
+        let mut playback = Playback::new(None, playlist);
+        playback.song = Some(0);
+        playback.skipped.insert(2);
+        for _ in 0..10 {
+            assert_eq!(random_song(&playback), Some(1));
+        }
+        playback.skipped.insert(1);
+        assert_eq!(random_song(&playback), None);
+    }
+
+    #[test]
+    fn loop_end_after_start() {
+        let mut playback = Playback::new(None, Playlist::new());
+        set_loop_end(&mut playback, Duration::from_secs(5));
+        assert_eq!(playback.loop_end, None);
+        playback.loop_start = Some(Duration::from_secs(10));
+        set_loop_end(&mut playback, Duration::from_secs(5));
+        assert_eq!(playback.loop_end, None);
+        set_loop_end(&mut playback, Duration::from_secs(20));
+        assert_eq!(playback.loop_end, Some(Duration::from_secs(20)));
+    }
+
+    #[test]
+    fn parse_invalid_commands() {
+        assert!(parse_command("dance").is_err());
+        assert!(parse_command("volume").is_err());
+        assert!(parse_command("volume loud").is_err());
+        assert!(parse_command("goto -1").is_err());
+        assert!(parse_command("pause now").is_err());
+        assert!(parse_command("goto 1 2").is_err());
+    }
+}
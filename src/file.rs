@@ -1,60 +1,340 @@
+use std::ffi::OsStr;
 use std::fs::File;
-use std::io::Write;
-use std::path::{Path, PathBuf};
+use std::io::{BufRead, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
 use std::{fs, io};
 
-use crate::playlist::{Playlist, Song};
+use serde::{Deserialize, Serialize};
+
+use crate::playlist::{natural_path_cmp, Playlist, Song};
 use crate::LibError;
+use crate::{audio, metadata};
+
+///How to collect songs from a directory
+#[derive(Clone, Copy)]
+pub struct DirectoryOptions {
+    ///Include songs in subdirectories
+    pub recursive: bool,
+    ///Skip files that are not sound files
+    pub filter: bool,
+}
 
-pub fn make_playlist_from_path(path: &Path) -> Result<Playlist, LibError> {
-    let songs = load_songs(path)?;
+pub fn make_playlist_from_path(
+    path: &Path, options: DirectoryOptions,
+) -> Result<Playlist, LibError> {
+    let mut songs = load_songs(path, options)?;
+    metadata::tag_songs(&mut songs);
 
     let mut p = Playlist::new();
-    for song in songs {
-        if let Err(e) = p.add_song(song) {
-            eprintln!("Error adding song: {e}");
-        }
-    }
+    p.add_songs(songs);
     Ok(p)
 }
 
-pub fn load_songs(path: &Path) -> Result<Vec<Song>, LibError> {
+///A single file is always loaded, `options` only apply to directories and glob patterns.
+///Songs of a directory are sorted by path, numbers by their value, so albums play in order.
+///Random modes still shuffle them.
+pub fn load_songs(path: &Path, options: DirectoryOptions) -> Result<Vec<Song>, LibError> {
     if path.is_file() {
         Ok(vec![Song::new(PathBuf::from(path))])
     } else if path.is_dir() {
-        let songs = load_songs_from_directory(path);
+        let songs = load_songs_from_directory(path, options);
         match songs {
-            Ok(s) => Ok(s),
-            Err(e) => Err(LibError(
-                String::from("Unable to read songs from directory"),
-                Some(Box::new(e)),
-            )),
+            Ok(mut s) => {
+                // Directories are read in no particular order
+                s.sort_by(|a, b| natural_path_cmp(&a.path, &b.path));
+                Ok(s)
+            }
+            Err(e) => Err(LibError::Io("Unable to read songs from directory", e)),
         }
+    } else if let Some(pattern) = path.to_str().filter(|p| p.contains(['*', '?', '['])) {
+        load_songs_from_glob(pattern, options)
     } else {
-        Err(LibError::new(String::from("Expected file or directory")))
+        Err(LibError::FileNotFound(path.to_path_buf()))
     }
 }
 
-fn load_songs_from_directory(path: &Path) -> Result<Vec<Song>, io::Error> {
+///`**` matches any number of directories, so `options.recursive` does not apply.
+fn load_songs_from_glob(pattern: &str, options: DirectoryOptions) -> Result<Vec<Song>, LibError> {
+    let paths = glob::glob(pattern)
+        .map_err(|e| LibError::InvalidArgument(format!("Invalid pattern {pattern}: {e}")))?;
+    let songs: Vec<Song> = paths
+        .filter_map(Result::ok)
+        .filter(|p| p.is_file() && (!options.filter || audio::likely_audio_file(p)))
+        .map(Song::new)
+        .collect();
+    if songs.is_empty() {
+        return Err(LibError::NoFilesMatched(String::from(pattern)));
+    }
+    Ok(songs)
+}
+
+fn load_songs_from_directory(
+    path: &Path, options: DirectoryOptions,
+) -> Result<Vec<Song>, io::Error> {
     let mut songs = vec![];
 
     let paths = path.read_dir()?;
     for path in paths {
-        let p = path?.path();
+        let path = path?;
+        let p = path.path();
         if p.is_file() {
-            songs.push(Song::new(p));
+            if !options.filter || audio::likely_audio_file(&p) {
+                songs.push(Song::new(p));
+            }
+        } else if options.recursive && path.file_type()?.is_dir() {
+            // Symlinked directories are not followed, so we can't end up in a loop
+            songs.append(&mut load_songs_from_directory(&p, options)?);
+        }
+    }
+
+    Ok(songs)
+}
+
+///Load songs from a M3U or extended M3U playlist, with the duration of `#EXTINF` lines.
+///Their title is only used for files without tags, unless it is just the file name.
+///Relative paths are resolved against the directory of the M3U file, missing files are skipped.
+pub fn load_m3u(path: &Path) -> Result<Vec<Song>, LibError> {
+    let data = fs::read_to_string(path).map_err(|e| LibError::Io("Error reading M3U file", e))?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+
+    let mut songs = vec![];
+    let mut extinf = None;
+    for line in data.lines() {
+        let line = line.trim().trim_start_matches('\u{feff}');
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            extinf = Some(parse_extinf(info));
+            continue;
+        }
+        // Comments and other extended M3U directives carry no information we store
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (duration, title) = extinf.take().unwrap_or_default();
+        let song_path = stored_path(dir, Path::new(line));
+        if song_path.is_file() {
+            let mut song = Song::new(song_path);
+            song.duration = duration;
+            if let Some(tags) = metadata::read(&song.path) {
+                song.set_tags(tags);
+            }
+            // Our own export writes the file name for songs without title
+            let file_name = song.path.file_name().and_then(OsStr::to_str);
+            if song.title.is_none() && title.as_deref() != file_name {
+                song.title = title;
+            }
+            songs.push(song);
+        } else {
+            eprintln!("Skipping missing file: {}", song_path.display());
         }
     }
+    Ok(songs)
+}
+
+///Duration and title of `#EXTINF:<seconds>,<title>`, for the path on the next line.
+///Negative durations like -1 mean unknown.
+fn parse_extinf(info: &str) -> (Option<Duration>, Option<String>) {
+    let (seconds, title) = info.split_once(',').unwrap_or((info, ""));
+    // Attributes like tvg-id="..." may follow the duration
+    let duration = seconds
+        .split_whitespace()
+        .next()
+        .and_then(|s| s.parse::<f64>().ok())
+        .and_then(|s| Duration::try_from_secs_f64(s).ok());
+    let title = Some(title.trim())
+        .filter(|t| !t.is_empty())
+        .map(String::from);
+    (duration, title)
+}
 
+///One song per line with its path, like the output of `find`.
+///Empty lines are ignored, missing files skipped.
+pub fn read_song_list(reader: impl BufRead) -> Result<Vec<Song>, LibError> {
+    let mut songs = vec![];
+    for line in reader.lines() {
+        let line = line.map_err(|e| LibError::Io("Error reading song list", e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let song_path = PathBuf::from(line.trim_end_matches('\r'));
+        if song_path.is_file() {
+            songs.push(Song::new(song_path));
+        } else {
+            eprintln!("Skipping missing file: {}", song_path.display());
+        }
+    }
     Ok(songs)
 }
 
-pub fn save_playlist(playlist: &Playlist, path: &PathBuf) -> Result<(), LibError> {
-    let playlist = serde_json::to_string(playlist).unwrap();
+///Canonical path if the file exists, otherwise just made absolute
+pub fn absolute_path(path: &Path) -> PathBuf {
+    fs::canonicalize(path)
+        .or_else(|_| std::path::absolute(path))
+        .unwrap_or_else(|_| path.to_path_buf())
+}
+
+///Save as extended M3U. Song paths are absolute, or relative to `base` if given.
+pub fn save_m3u(playlist: &Playlist, path: &Path, base: Option<&Path>) -> Result<(), LibError> {
+    let base = base.map(|b| fs::canonicalize(b).unwrap_or_else(|_| PathBuf::from(b)));
+
+    let write_m3u = |mut o: File| -> Result<(), io::Error> {
+        writeln!(o, "#EXTM3U")?;
+        for i in 0..playlist.song_count() {
+            let song = playlist.song(i).unwrap();
+            // Missing songs can't be canonicalized, so we keep them as they are
+            let song_path = fs::canonicalize(&song.path).unwrap_or_else(|_| song.path.clone());
+            let song_path = match &base {
+                Some(b) => song_path.strip_prefix(b).unwrap_or(&song_path),
+                None => &song_path,
+            };
+            let seconds = song.duration.map_or(-1, |d| i128::from(d.as_secs()));
+            writeln!(o, "#EXTINF:{seconds},{song}\n{}", song_path.display())?;
+        }
+        Ok(())
+    };
 
     File::create(path)
-        .and_then(|mut o| write!(o, "{playlist}"))
-        .map_err(|e| LibError(String::from("Error writing playlist"), Some(Box::new(e))))
+        .and_then(write_m3u)
+        .map_err(|e| LibError::Io("Error writing M3U file", e))
+}
+
+///Playlists ending in `.yaml` or `.yml` are stored as YAML, all others as JSON.
+///YAML is easier to edit by hand, but comments are lost once the playlist is saved again.
+fn is_yaml(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(OsStr::to_str),
+        Some("yaml" | "yml")
+    )
+}
+
+///Relative song paths are stored relative to the playlist, so it can be moved along with its songs.
+///Absolute paths are stored as they are. The format depends on the extension, see `is_yaml`.
+pub fn save_playlist(playlist: &Playlist, path: &Path) -> Result<(), LibError> {
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let mut playlist = playlist.clone();
+    for i in 0..playlist.song_count() {
+        let song = playlist.song_mut(i).unwrap();
+        if song.path.is_relative() {
+            song.path = relative_to(&song.path, dir);
+        }
+    }
+    let playlist = if is_yaml(path) {
+        // Only maps, strings and numbers, so this can't fail
+        serde_yaml::to_string(&playlist).unwrap()
+    } else {
+        serde_json::to_string(&playlist).unwrap()
+    };
+
+    write_atomic(path, &playlist).map_err(|e| LibError::Io("Error writing playlist", e))
+}
+
+///Write to a temporary file next to `path` first and rename it over `path` once complete.
+///If writing fails halfway, the old file stays as it was.
+fn write_atomic(path: &Path, data: &str) -> Result<(), io::Error> {
+    let temp = sidecar_path(path, ".tmp");
+    let result = File::create(&temp)
+        .and_then(|mut o| o.write_all(data.as_bytes()).and_then(|()| o.sync_all()))
+        .and_then(|()| fs::rename(&temp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+///Where playback of a playlist stopped, to continue there next time
+#[derive(Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct ResumePoint {
+    ///Index of the song in the playlist
+    pub index: usize,
+    pub position: Duration,
+}
+
+///The playlist as it was before the last edit, next to it with `.bak` appended to its name
+pub fn backup_path(playlist: &Path) -> PathBuf {
+    sidecar_path(playlist, ".bak")
+}
+
+///Keep a copy of the playlist file at `backup_path` before it is overwritten.
+///Anything is copied, even a file that is no valid playlist. Nothing to do if it doesn't exist.
+pub fn backup_playlist(path: &Path) -> Result<(), LibError> {
+    if !path.exists() {
+        return Ok(());
+    }
+    fs::copy(path, backup_path(path))
+        .map(|_| ())
+        .map_err(|e| LibError::Io("Error backing up playlist", e))
+}
+
+///Swap the playlist with its backup, so restoring twice undoes the restore
+pub fn restore_backup(path: &Path) -> Result<(), LibError> {
+    let backup = backup_path(path);
+    if !backup.exists() {
+        let message = format!("There is no backup at {}", backup.display());
+        return Err(LibError::Io(
+            "Nothing to undo",
+            io::Error::new(io::ErrorKind::NotFound, message),
+        ));
+    }
+    let error = |e| LibError::Io("Error restoring backup", e);
+    if !path.exists() {
+        return fs::rename(&backup, path).map_err(error);
+    }
+    let temp = sidecar_path(path, ".tmp");
+    fs::rename(path, &temp).map_err(error)?;
+    fs::rename(&backup, path).map_err(error)?;
+    fs::rename(&temp, &backup).map_err(error)
+}
+
+///Next to the playlist, with `.resume` appended to its name
+pub fn resume_path(playlist: &Path) -> PathBuf {
+    sidecar_path(playlist, ".resume")
+}
+
+fn sidecar_path(playlist: &Path, suffix: &str) -> PathBuf {
+    let mut path = playlist.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+pub fn save_resume(point: &ResumePoint, path: &Path) -> Result<(), LibError> {
+    let point = serde_json::to_string(point).unwrap();
+
+    write_atomic(path, &point).map_err(|e| LibError::Io("Error writing resume point", e))
+}
+
+pub fn load_resume(path: &Path) -> Result<ResumePoint, LibError> {
+    let data =
+        fs::read_to_string(path).map_err(|e| LibError::Io("Error reading resume point", e))?;
+    serde_json::from_str(data.as_str())
+        .map_err(|e| LibError::Deserialize("Error deserializing resume point", e))
+}
+
+///A position in a song to jump back to
+#[derive(Debug, PartialEq, Clone)]
+#[derive(Serialize, Deserialize)]
+pub struct Bookmark {
+    pub name: String,
+    ///Index of the song in the playlist
+    pub index: usize,
+    pub position: Duration,
+}
+
+///Next to the playlist, with `.bookmarks` appended to its name
+pub fn bookmarks_path(playlist: &Path) -> PathBuf {
+    sidecar_path(playlist, ".bookmarks")
+}
+
+pub fn save_bookmarks(bookmarks: &[Bookmark], path: &Path) -> Result<(), LibError> {
+    let bookmarks = serde_json::to_string(bookmarks).unwrap();
+
+    write_atomic(path, &bookmarks).map_err(|e| LibError::Io("Error writing bookmarks", e))
+}
+
+pub fn load_bookmarks(path: &Path) -> Result<Vec<Bookmark>, LibError> {
+    let data = fs::read_to_string(path).map_err(|e| LibError::Io("Error reading bookmarks", e))?;
+    serde_json::from_str(data.as_str())
+        .map_err(|e| LibError::Deserialize("Error deserializing bookmarks", e))
 }
 
 pub fn load_playlist(path: &PathBuf) -> Result<Playlist, LibError> {
@@ -62,24 +342,242 @@ pub fn load_playlist(path: &PathBuf) -> Result<Playlist, LibError> {
     let data = match data {
         Ok(d) => d,
         Err(e) => {
-            return Err(LibError(
-                String::from("Error reading playlist"),
-                Some(Box::new(e)),
-            ));
+            return Err(LibError::Io("Error reading playlist", e));
         }
     };
 
-    serde_json::from_str(data.as_str()).map_err(|e| {
-        LibError(
-            String::from("Error deserializing playlist"),
-            Some(Box::new(e)),
-        )
-    })
+    let mut playlist: Playlist = if is_yaml(path) {
+        serde_yaml::from_str(data.as_str())
+            .map_err(|e| LibError::DeserializeYaml("Error deserializing playlist", e))?
+    } else {
+        serde_json::from_str(data.as_str())
+            .map_err(|e| LibError::Deserialize("Error deserializing playlist", e))?
+    };
+    // Relative paths are stored relative to the playlist, see `save_playlist`
+    let dir = path.parent().unwrap_or(Path::new(""));
+    for i in 0..playlist.song_count() {
+        let song = playlist.song_mut(i).unwrap();
+        song.path = stored_path(dir, &song.path);
+    }
+    Ok(playlist)
+}
+
+///Path of a song stored in a playlist in `dir`.
+///Playlists made on Windows separate relative paths with backslashes, which are part of the
+///file name elsewhere. They are converted, unless a file with backslashes in its name exists.
+///Absolute Windows paths, with a drive letter or on a server, are kept as they are,
+///as they can't point to a file here anyway.
+#[cfg(not(windows))]
+fn stored_path(dir: &Path, path: &Path) -> PathBuf {
+    let joined = dir.join(path);
+    let Some(text) = path.to_str().filter(|t| t.contains('\\')) else {
+        return joined;
+    };
+    let bytes = text.as_bytes();
+    let drive = bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':';
+    if drive || text.starts_with('\\') || joined.exists() {
+        return joined;
+    }
+    dir.join(text.replace('\\', "/"))
+}
+
+///Windows takes both separators
+#[cfg(windows)]
+fn stored_path(dir: &Path, path: &Path) -> PathBuf {
+    dir.join(path)
+}
+
+///`path` relative to `dir`. Both are relative to the working directory, or both absolute.
+fn relative_to(path: &Path, dir: &Path) -> PathBuf {
+    if let Ok(p) = path.strip_prefix(dir) {
+        return p.to_path_buf();
+    }
+    let (Ok(path), Ok(dir)) = (std::path::absolute(path), std::path::absolute(dir)) else {
+        return path.to_path_buf();
+    };
+    let (path, dir) = (normalize(&path), normalize(&dir));
+    let common = path
+        .components()
+        .zip(dir.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    if common == 0 {
+        // Nothing in common, like different drives on Windows
+        return path;
+    }
+    let mut relative = PathBuf::new();
+    for _ in dir.components().skip(common) {
+        relative.push("..");
+    }
+    relative.extend(path.components().skip(common));
+    relative
+}
+
+///Resolve `.` and `..` without touching the file system
+fn normalize(path: &Path) -> PathBuf {
+    let mut normal = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                normal.pop();
+            }
+            c => normal.push(c),
+        }
+    }
+    normal
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::RandomMode;
+    use crate::playlist::SongConfig;
+
+    #[test]
+    fn load_directory_flat() {
+        let options = DirectoryOptions {
+            recursive: false,
+            filter: false,
+        };
+        let songs =
+            load_songs(Path::new("test_data"), options).expect("test_data/ should be readable");
+        assert!(songs
+            .iter()
+            .all(|s| s.path.parent() == Some(Path::new("test_data"))));
+    }
+
+    #[test]
+    fn load_directory_recursive() {
+        let options = DirectoryOptions {
+            recursive: true,
+            filter: false,
+        };
+        let songs =
+            load_songs(Path::new("test_data"), options).expect("test_data/ should be readable");
+        assert!(songs
+            .iter()
+            .any(|s| s.path == Path::new("test_data/nested/silence.wav")));
+    }
+
+    #[test]
+    #[cfg(feature = "mp3")]
+    fn load_directory_filtered() {
+        let options = DirectoryOptions {
+            recursive: false,
+            filter: true,
+        };
+        let songs =
+            load_songs(Path::new("test_data"), options).expect("test_data/ should be readable");
+        assert_eq!(songs, vec![Song::new(PathBuf::from("test_data/test.mp3"))]);
+    }
+
+    #[test]
+    #[cfg(feature = "mp3")]
+    fn load_directory_in_track_order() {
+        let dir = Path::new("test_track_order");
+        fs::create_dir_all(dir.join("cd2")).unwrap();
+        let names = ["track10.mp3", "Track2.mp3", "track1.mp3", "cd2/track1.mp3"];
+        for name in names {
+            File::create(dir.join(name)).unwrap();
+        }
+        let options = DirectoryOptions {
+            recursive: true,
+            filter: true,
+        };
+        let songs = load_songs(dir, options);
+        fs::remove_dir_all(dir).unwrap();
+
+        let paths: Vec<PathBuf> = songs.unwrap().into_iter().map(|s| s.path).collect();
+        let expected: Vec<PathBuf> = ["cd2/track1.mp3", "track1.mp3", "Track2.mp3", "track10.mp3"]
+            .iter()
+            .map(|name| dir.join(name))
+            .collect();
+        assert_eq!(paths, expected);
+    }
+
+    #[test]
+    #[cfg(feature = "wav")]
+    fn load_glob() {
+        let options = DirectoryOptions {
+            recursive: false,
+            filter: true,
+        };
+        let songs =
+            load_songs(Path::new("test_data/**/*.wav"), options).expect("Pattern should match");
+        assert_eq!(
+            songs,
+            vec![Song::new(PathBuf::from("test_data/nested/silence.wav"))]
+        );
+    }
+
+    #[test]
+    fn load_glob_no_match() -> Result<(), &'static str> {
+        let options = DirectoryOptions {
+            recursive: false,
+            filter: false,
+        };
+        match load_songs(Path::new("test_data/*.flac"), options) {
+            Err(LibError::NoFilesMatched(_)) => Ok(()),
+            Err(_) => Err("Pattern without matches should give NoFilesMatched."),
+            Ok(_) => Err("Pattern without matches should give error."),
+        }
+    }
+
+    #[test]
+    fn valid_load_m3u() {
+        let songs =
+            load_m3u(Path::new("test_data/test.m3u")).expect("test_data/ should be readable");
+        assert_eq!(
+            songs,
+            vec![
+                Song {
+                    duration: Some(Duration::from_secs(6)),
+                    title: Some(String::from("Test")),
+                    ..Song::new(PathBuf::from("test_data/test.mp3"))
+                },
+                Song {
+                    title: Some(String::from("Silence")),
+                    ..Song::new(PathBuf::from("test_data/nested/silence.wav"))
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_extinf_lines() {
+        assert_eq!(
+            parse_extinf("12.5,Artist - Title"),
+            (
+                Some(Duration::from_millis(12_500)),
+                Some(String::from("Artist - Title"))
+            )
+        );
+        assert_eq!(
+            parse_extinf(r#"-1 tvg-id="x",Radio"#),
+            (None, Some(String::from("Radio")))
+        );
+        assert_eq!(parse_extinf("invalid"), (None, None));
+    }
+
+    #[test]
+    fn valid_save_load_m3u() {
+        let path = Path::new("test_export.m3u");
+        let mut p = Playlist::new();
+        p.add_song(Song::new(PathBuf::from("test_data/test.mp3")))
+            .unwrap();
+        p.song_mut(0).unwrap().duration = Some(Duration::from_secs(3));
+        save_m3u(&p, path, None).expect("Saving in working directory should work");
+        let songs = load_m3u(path).expect("Loading saved M3U should work");
+        assert_eq!(
+            songs,
+            vec![Song {
+                duration: Some(Duration::from_secs(3)),
+                ..Song::new(fs::canonicalize("test_data/test.mp3").unwrap())
+            }]
+        );
+        fs::remove_file(path).unwrap();
+    }
 
     #[test]
     fn valid_deserialize_empty_list() {
@@ -88,6 +586,41 @@ mod tests {
         assert_eq!(p, Playlist::new());
     }
 
+    #[test]
+    fn valid_deserialize_missing_fields() {
+        let p: Playlist = serde_json::from_str(r#"{"songs":[{"path":"a.mp3","config":{}}]}"#)
+            .expect("Missing fields should fall back to defaults");
+        assert_eq!(p.song(0).unwrap().config, SongConfig::new());
+        assert_eq!(p.config, Playlist::new().config);
+    }
+
+    #[test]
+    fn valid_save_load_resume() {
+        let path = Path::new("test.resume");
+        let point = ResumePoint {
+            index: 3,
+            position: Duration::from_millis(12_345),
+        };
+        save_resume(&point, path).expect("Saving in working directory should work");
+        let loaded = load_resume(path).expect("Loading saved resume point should work");
+        assert_eq!(point, loaded);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn valid_save_load_bookmarks() {
+        let path = Path::new("test.bookmarks");
+        let bookmarks = vec![Bookmark {
+            name: String::from("Chapter 2"),
+            index: 1,
+            position: Duration::from_secs(90),
+        }];
+        save_bookmarks(&bookmarks, path).expect("Saving in working directory should work");
+        let loaded = load_bookmarks(path).expect("Loading saved bookmarks should work");
+        assert_eq!(bookmarks, loaded);
+        fs::remove_file(path).unwrap();
+    }
+
     #[test]
     fn valid_de_serialize_empty_list() {
         let path = &PathBuf::from("test.playlist");
@@ -96,4 +629,129 @@ mod tests {
         let p2 = load_playlist(path).expect("Loading saved playlist should work");
         assert_eq!(p1, p2);
     }
+
+    #[test]
+    fn failed_save_keeps_playlist() {
+        let path = &PathBuf::from("test_atomic.playlist");
+        let mut p = Playlist::new();
+        p.add_song(Song::new(PathBuf::from("test_data/test.mp3")))
+            .unwrap();
+        save_playlist(&p, path).expect("Saving in working directory should work");
+        // Writing the temporary file fails if a directory is in the way
+        let temp = sidecar_path(path, ".tmp");
+        fs::create_dir(&temp).unwrap();
+        let failed = save_playlist(&Playlist::new(), path);
+        let loaded = load_playlist(path);
+        fs::remove_dir(&temp).unwrap();
+        fs::remove_file(path).unwrap();
+
+        assert!(failed.is_err());
+        assert_eq!(loaded.expect("Old playlist should still load"), p);
+    }
+
+    #[test]
+    fn valid_read_song_list() {
+        let list = "test_data/test.mp3\n\ntest_data/missing.mp3\ntest_data/nested/silence.wav\n";
+        let songs = read_song_list(list.as_bytes()).expect("Reading from memory should work");
+        let paths: Vec<&Path> = songs.iter().map(|s| s.path.as_path()).collect();
+        assert_eq!(
+            paths,
+            [
+                Path::new("test_data/test.mp3"),
+                Path::new("test_data/nested/silence.wav")
+            ]
+        );
+    }
+
+    #[test]
+    fn keep_unknown_fields() {
+        let path = &PathBuf::from("test_unknown.playlist");
+        let data = r#"{"config":{"volume":1.0,"random":"Off","eq":"bass"},"songs":[{"path":"a.mp3","config":{"pan":-0.5},"rating":4}],"name":"Mix"}"#;
+        fs::write(path, data).unwrap();
+        let p = load_playlist(path).expect("Unknown fields should be ignored");
+        save_playlist(&p, path).expect("Saving in working directory should work");
+        let saved: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(saved["name"], "Mix");
+        assert_eq!(saved["config"]["eq"], "bass");
+        assert_eq!(saved["songs"][0]["rating"], 4);
+        assert_eq!(saved["songs"][0]["config"]["pan"], -0.5);
+    }
+
+    #[test]
+    fn valid_de_serialize_yaml() {
+        let path = &PathBuf::from("test_yaml.yaml");
+        let mut p1 = Playlist::new();
+        p1.config.volume = 0.5;
+        p1.config.fade = Some(Duration::from_secs(2));
+        let mut song = Song::new(PathBuf::from("test_data/test.mp3"));
+        song.config.start = Some(Duration::from_millis(1500));
+        p1.add_song(song).unwrap();
+        save_playlist(&p1, path).expect("Saving in working directory should work");
+        let data = fs::read_to_string(path).unwrap();
+        let p2 = load_playlist(path);
+        fs::remove_file(path).unwrap();
+
+        assert!(data.contains("volume: 0.5"));
+        assert_eq!(p2.expect("Loading saved playlist should work"), p1);
+    }
+
+    #[test]
+    fn load_yaml_with_comments() {
+        let path = &PathBuf::from("test_data/commented.yml");
+        let p = load_playlist(path).expect("Loading test playlist should work");
+        assert_eq!(p.config.random, RandomMode::Shuffle);
+        assert_eq!(p.song_count(), 2);
+        assert_eq!(
+            p.song(1).unwrap().path,
+            PathBuf::from("test_data/nested/silence.wav")
+        );
+    }
+
+    #[test]
+    fn paths_relative_to_playlist() {
+        let path = &PathBuf::from("test_data/nested/relative.playlist");
+        let p = load_playlist(path).expect("Loading test playlist should work");
+        let expected = PathBuf::from("test_data/nested/silence.wav");
+        assert_eq!(p.song(0).unwrap().path, expected);
+        assert!(p.song(0).unwrap().path.exists());
+
+        let path = &PathBuf::from("test_data/nested/test.playlist");
+        save_playlist(&p, path).expect("Saving in test directory should work");
+        let data = fs::read_to_string(path).unwrap();
+        let loaded = load_playlist(path);
+        fs::remove_file(path).unwrap();
+        assert!(data.contains(r#""path":"silence.wav""#));
+        assert_eq!(loaded.unwrap().song(0).unwrap().path, expected);
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn windows_separators() {
+        let path = &PathBuf::from("test_data/windows.playlist");
+        let p = load_playlist(path).expect("Loading test playlist should work");
+        assert_eq!(
+            p.song(0).unwrap().path,
+            PathBuf::from("test_data/nested/silence.wav")
+        );
+        assert!(p.song(0).unwrap().path.exists());
+        assert_eq!(
+            p.song(1).unwrap().path,
+            PathBuf::from("test_data/C:\\Music\\a.mp3")
+        );
+        assert_eq!(
+            p.song(2).unwrap().path,
+            PathBuf::from("test_data/\\\\server\\share\\b.mp3")
+        );
+    }
+
+    #[test]
+    fn relative_to_parent() {
+        let path = Path::new("test_data/test.mp3");
+        let dir = Path::new("test_data/nested");
+        assert_eq!(relative_to(path, dir), PathBuf::from("../test.mp3"));
+        assert_eq!(relative_to(path, Path::new("")), path);
+    }
 }
@@ -0,0 +1,286 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use serde::Deserialize;
+
+use crate::LibError;
+
+///What a key does while playing
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Quit,
+    Help,
+    Pause,
+    VolumeUp,
+    VolumeDown,
+    Next,
+    Previous,
+    SeekForward,
+    SeekBack,
+    Faster,
+    Slower,
+    RepeatSong,
+    Mute,
+    Save,
+    ///Go to the song with the number typed before
+    Goto,
+    Queue,
+    Bookmark,
+    Bookmarks,
+    GotoBookmark,
+    Sleep,
+    ///Start a loop of a section of the current song at the current position
+    LoopStart,
+    ///End the loop at the current position and start looping
+    LoopEnd,
+    LoopClear,
+    ///Go to a song picked at random, whatever the random mode
+    RandomJump,
+    ///Rate the current song a star more, after the most stars it is unrated again
+    Rate,
+}
+
+///Vim-style hjkl work like the arrow keys, for terminals that don't pass arrows through.
+///That is why help is on `?` instead of `h`.
+const DEFAULTS: [(Action, &[&str]); 25] = [
+    (Action::Quit, &["q"]),
+    (Action::Help, &["?"]),
+    (Action::Pause, &["space"]),
+    (Action::VolumeUp, &["up", "k"]),
+    (Action::VolumeDown, &["down", "j"]),
+    (Action::Next, &["right", "l"]),
+    (Action::Previous, &["left", "h"]),
+    (Action::SeekForward, &["shift+right"]),
+    (Action::SeekBack, &["shift+left"]),
+    (Action::Faster, &["]"]),
+    (Action::Slower, &["["]),
+    (Action::RepeatSong, &["r"]),
+    (Action::Mute, &["m"]),
+    (Action::Save, &["s"]),
+    (Action::Goto, &["enter"]),
+    (Action::Queue, &["e"]),
+    (Action::Bookmark, &["b"]),
+    (Action::Bookmarks, &["v"]),
+    (Action::GotoBookmark, &["g"]),
+    (Action::Sleep, &["z"]),
+    (Action::LoopStart, &["a"]),
+    (Action::LoopEnd, &["d"]),
+    (Action::LoopClear, &["c"]),
+    (Action::RandomJump, &["x"]),
+    (Action::Rate, &["*"]),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Key {
+    code: KeyCode,
+    shift: bool,
+}
+
+impl Key {
+    fn parse(name: &str) -> Result<Key, LibError> {
+        let (shift, rest) = match name.strip_prefix("shift+") {
+            Some(rest) => (true, rest),
+            None => (false, name),
+        };
+        let code = match rest {
+            "space" => KeyCode::Char(' '),
+            "up" => KeyCode::Up,
+            "down" => KeyCode::Down,
+            "left" => KeyCode::Left,
+            "right" => KeyCode::Right,
+            "enter" => KeyCode::Enter,
+            "tab" => KeyCode::Tab,
+            "esc" => KeyCode::Esc,
+            "home" => KeyCode::Home,
+            "end" => KeyCode::End,
+            "pageup" => KeyCode::PageUp,
+            "pagedown" => KeyCode::PageDown,
+            c if c.chars().count() == 1 && !shift => {
+                let c = c.chars().next().unwrap();
+                if c.is_ascii_digit() {
+                    return Err(LibError::InvalidArgument(format!(
+                        "Digits are used to type song numbers and can't be bound: {name}"
+                    )));
+                }
+                KeyCode::Char(c)
+            }
+            _ => return Err(LibError::InvalidArgument(format!("Unknown key: {name}"))),
+        };
+        Ok(Key { code, shift })
+    }
+
+    fn matches(&self, event: &KeyEvent) -> bool {
+        // Characters already tell whether shift was held
+        self.code == event.code
+            && (matches!(self.code, KeyCode::Char(_))
+                || self.shift == event.modifiers.contains(KeyModifiers::SHIFT))
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.shift {
+            write!(f, "shift+")?;
+        }
+        match self.code {
+            KeyCode::Char(' ') => write!(f, "space"),
+            KeyCode::Char(c) => write!(f, "{c}"),
+            KeyCode::Up => write!(f, "\u{2191}"),
+            KeyCode::Down => write!(f, "\u{2193}"),
+            KeyCode::Left => write!(f, "\u{2190}"),
+            KeyCode::Right => write!(f, "\u{2192}"),
+            KeyCode::PageUp => write!(f, "pageup"),
+            KeyCode::PageDown => write!(f, "pagedown"),
+            code => write!(f, "{}", format!("{code:?}").to_lowercase()),
+        }
+    }
+}
+
+///One key or a list of keys for an action in the keymap file
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Keys {
+    One(String),
+    Many(Vec<String>),
+}
+
+///Which keys trigger which action while playing
+#[derive(Clone, Debug, PartialEq)]
+pub struct Keymap {
+    bindings: Vec<(Action, Vec<Key>)>,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        let bindings = DEFAULTS
+            .iter()
+            .map(|(action, keys)| {
+                (
+                    *action,
+                    keys.iter().map(|k| Key::parse(k).unwrap()).collect(),
+                )
+            })
+            .collect();
+        Keymap { bindings }
+    }
+}
+
+impl Keymap {
+    ///Read key bindings from a JSON file like `{"next": "n", "volume_up": ["up", "+"]}`.
+    ///Actions that are left out keep their default keys.
+    pub fn load(path: &Path) -> Result<Keymap, LibError> {
+        let data = fs::read_to_string(path).map_err(|e| LibError::Io("Error reading keymap", e))?;
+        let changed: HashMap<Action, Keys> = serde_json::from_str(data.as_str())
+            .map_err(|e| LibError::Deserialize("Error deserializing keymap", e))?;
+        Keymap::default().with(changed)
+    }
+
+    fn with(mut self, changed: HashMap<Action, Keys>) -> Result<Keymap, LibError> {
+        for (action, keys) in changed {
+            let keys = match keys {
+                Keys::One(key) => vec![key],
+                Keys::Many(keys) => keys,
+            };
+            let keys = keys
+                .iter()
+                .map(|k| Key::parse(k))
+                .collect::<Result<Vec<_>, _>>()?;
+            let binding = self.bindings.iter_mut().find(|(a, _)| *a == action);
+            // Every action has a default binding
+            binding.unwrap().1 = keys;
+        }
+        for (i, (action, keys)) in self.bindings.iter().enumerate() {
+            for key in keys {
+                let other = self.bindings[i + 1..]
+                    .iter()
+                    .find(|(_, other)| other.contains(key));
+                if let Some((other, _)) = other {
+                    return Err(LibError::InvalidArgument(format!(
+                        "Key {key} is bound to both {action:?} and {other:?}"
+                    )));
+                }
+            }
+        }
+        Ok(self)
+    }
+
+    ///The action `event` triggers, if any
+    pub fn action(&self, event: &KeyEvent) -> Option<Action> {
+        self.bindings
+            .iter()
+            .find(|(_, keys)| keys.iter().any(|k| k.matches(event)))
+            .map(|(action, _)| *action)
+    }
+
+    ///Keys of `action` for the help, like `a,b`. `-` if it has none.
+    pub fn keys(&self, action: Action) -> String {
+        let (_, keys) = self.bindings.iter().find(|(a, _)| *a == action).unwrap();
+        if keys.is_empty() {
+            return String::from("-");
+        }
+        let keys: Vec<String> = keys.iter().map(ToString::to_string).collect();
+        keys.join(",")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn press(code: KeyCode, modifiers: KeyModifiers) -> KeyEvent {
+        KeyEvent::new(code, modifiers)
+    }
+
+    #[test]
+    fn default_keys() {
+        let keymap = Keymap::default();
+        let right = press(KeyCode::Right, KeyModifiers::NONE);
+        let shift_right = press(KeyCode::Right, KeyModifiers::SHIFT);
+        assert_eq!(keymap.action(&right), Some(Action::Next));
+        assert_eq!(keymap.action(&shift_right), Some(Action::SeekForward));
+        let q = press(KeyCode::Char('q'), KeyModifiers::NONE);
+        assert_eq!(keymap.action(&q), Some(Action::Quit));
+        assert_eq!(keymap.keys(Action::SeekBack), "shift+\u{2190}");
+    }
+
+    #[test]
+    fn vim_keys() {
+        let keymap = Keymap::default();
+        let key = |c| keymap.action(&press(KeyCode::Char(c), KeyModifiers::NONE));
+        assert_eq!(key('k'), Some(Action::VolumeUp));
+        assert_eq!(key('j'), Some(Action::VolumeDown));
+        assert_eq!(key('l'), Some(Action::Next));
+        assert_eq!(key('h'), Some(Action::Previous));
+        assert_eq!(key('?'), Some(Action::Help));
+        assert_eq!(keymap.keys(Action::VolumeUp), "\u{2191},k");
+    }
+
+    #[test]
+    fn remap_keys() {
+        let changed = serde_json::from_str(r#"{"next": ["n", "y"], "save": []}"#).unwrap();
+        let keymap = Keymap::default().with(changed).unwrap();
+        let n = press(KeyCode::Char('n'), KeyModifiers::NONE);
+        let right = press(KeyCode::Right, KeyModifiers::NONE);
+        assert_eq!(keymap.action(&n), Some(Action::Next));
+        assert_eq!(keymap.action(&right), None);
+        assert_eq!(keymap.keys(Action::Next), "n,y");
+        assert_eq!(keymap.keys(Action::Save), "-");
+    }
+
+    #[test]
+    fn invalid_keymap() {
+        let changed = serde_json::from_str(r#"{"next": "s"}"#).unwrap();
+        assert!(Keymap::default().with(changed).is_err());
+        let changed = serde_json::from_str(r#"{"help": "h"}"#).unwrap();
+        assert!(Keymap::default().with(changed).is_err());
+        let changed = serde_json::from_str(r#"{"next": "1"}"#).unwrap();
+        assert!(Keymap::default().with(changed).is_err());
+        let changed = serde_json::from_str(r#"{"next": "shift+n"}"#).unwrap();
+        assert!(Keymap::default().with(changed).is_err());
+        assert!(Keymap::load(Path::new("test_data/empty.playlist")).is_err());
+    }
+}
@@ -1,249 +1,782 @@
 #![deny(clippy::pedantic)]
-use std::fs::File;
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
-use std::sync::mpsc::Sender;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 use std::{error::Error, fmt};
 
-use rand::seq::SliceRandom;
-use rand::Rng;
-use rodio::{OutputStream, Sink};
+use rodio::Source;
 
-use crate::config::{Cli, Command, EditCommand, PlayCommand, RandomMode};
-use crate::controls::{ControlMessage, Playback};
-use crate::playlist::Playlist;
+use crate::config::{Cli, Command, DisplayCommand, EditCommand, PlayCommand, SortKey};
+use crate::controls::{Input, Playback, VolumeSteps};
+use crate::file::{DirectoryOptions, ResumePoint};
+use crate::keymap::Keymap;
+pub use crate::player::Player;
+use crate::playlist::{Filtered, Playlist, PlaylistConfig, Song, MAX_RATING};
 
 mod audio;
 pub mod config;
 mod controls;
 mod file;
-mod playlist;
+mod keymap;
+mod loudness;
+mod metadata;
+#[cfg(all(feature = "mpris", target_os = "linux"))]
+mod mpris;
+mod player;
+pub mod playlist;
+mod render;
+#[cfg(unix)]
+mod socket;
+mod status;
+#[cfg(feature = "tempo")]
+mod tempo;
 
-#[derive(Debug)]
 ///Error was handled, we just need to display it now.
-///May contain an actual error to display in verbose mode.
-pub struct LibError(pub String, pub Option<Box<dyn Error>>);
+#[derive(Debug)]
+pub enum LibError {
+    ///The path is neither a file nor a directory
+    FileNotFound(PathBuf),
+    ///No file matches the glob pattern
+    NoFilesMatched(String),
+    ///Reading or writing a file failed, with what we tried to do
+    Io(&'static str, io::Error),
+    ///A stored playlist or resume point is not valid JSON
+    Deserialize(&'static str, serde_json::Error),
+    ///Like `Deserialize`, for playlists stored as YAML
+    DeserializeYaml(&'static str, serde_yaml::Error),
+    ///The file is no audio file we can decode
+    DecodeFailed(String),
+    EmptyPlaylist,
+    ///No audio output could be used, with what we tried to do
+    AudioDevice(&'static str, Box<dyn Error + Send + Sync>),
+    ///An argument is out of range or refers to nothing
+    InvalidArgument(String),
+    ///Playback ended because something went wrong
+    Playback(&'static str),
+    ///Files of songs in the playlist don't exist anymore
+    MissingSongs(Vec<PathBuf>),
+    ///The user didn't confirm a change
+    Aborted,
+}
 
 impl LibError {
-    fn new(msg: String) -> Self {
-        Self(msg, None)
+    ///Exit code of the program for this error, so scripts can tell failures apart.
+    ///2 is what clap exits with on invalid arguments, we use it for invalid values as well.
+    #[must_use]
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            LibError::InvalidArgument(_) => 2,
+            LibError::FileNotFound(_) | LibError::NoFilesMatched(_) | LibError::MissingSongs(_) => {
+                3
+            }
+            LibError::Io(_, e) if e.kind() == io::ErrorKind::NotFound => 3,
+            LibError::DecodeFailed(_) => 4,
+            LibError::EmptyPlaylist => 5,
+            LibError::AudioDevice(..) => 6,
+            LibError::Aborted => 7,
+            _ => 1,
+        }
     }
 }
 
-impl Error for LibError {}
+impl Error for LibError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LibError::Io(_, e) => Some(e),
+            LibError::Deserialize(_, e) => Some(e),
+            LibError::DeserializeYaml(_, e) => Some(e),
+            LibError::AudioDevice(_, e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
 
 impl fmt::Display for LibError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match &self.1 {
-            None => write!(f, "{}", self.0),
-            Some(e) => write!(f, "{}: {}", self.0, e),
+        match self {
+            LibError::FileNotFound(_) => write!(f, "Expected file or directory"),
+            LibError::NoFilesMatched(pattern) => write!(f, "No files matched {pattern}"),
+            LibError::Io(msg, e) => write!(f, "{msg}: {e}"),
+            LibError::Deserialize(msg, e) => write!(f, "{msg}: {e}"),
+            LibError::DeserializeYaml(msg, e) => write!(f, "{msg}: {e}"),
+            LibError::DecodeFailed(msg) | LibError::InvalidArgument(msg) => write!(f, "{msg}"),
+            LibError::EmptyPlaylist => write!(f, "Playlist is empty"),
+            LibError::AudioDevice(msg, e) => write!(f, "{msg}: {e}"),
+            LibError::Playback(msg) => write!(f, "{msg}"),
+            LibError::MissingSongs(paths) => {
+                write!(f, "Missing songs: {}", display_paths(paths))
+            }
+            LibError::Aborted => write!(f, "Aborted, the playlist was not changed"),
         }
     }
 }
 
+fn display_paths(paths: &[PathBuf]) -> String {
+    let paths: Vec<String> = paths.iter().map(|p| p.display().to_string()).collect();
+    paths.join(", ")
+}
+
 #[allow(clippy::missing_errors_doc)]
 pub fn run(config: Cli) -> Result<(), LibError> {
     match config.command {
-        Command::Play(c) => play(&c),
-        Command::Edit(c) => {
-            let path = &PathBuf::from(&c.playlist);
-            let p = file::load_playlist(path).unwrap_or_else(|_| Playlist::new());
-            let p = edit_playlist(p, c)?;
-            file::save_playlist(&p, path)?;
+        Command::Play(c) => play(&c, config.quiet),
+        Command::Edit(c) => edit(c, io::stdin().is_terminal()),
+        Command::Display(c) => display(&c),
+        Command::Export(c) => {
+            let p = file::load_playlist(&PathBuf::from(&c.playlist))?;
+            file::save_m3u(&p, Path::new(&c.output), c.base.as_deref().map(Path::new))
+        }
+        Command::Formats => {
+            for format in audio::FORMATS {
+                let disabled = if format.enabled { "" } else { ", not built in" };
+                println!(
+                    "{} (.{}{disabled})",
+                    format.name,
+                    format.extensions.join(", .")
+                );
+            }
             Ok(())
         }
-        Command::Display(c) => {
-            println!("{}", file::load_playlist(&PathBuf::from(&c.playlist))?);
+        Command::Devices => {
+            for name in audio::device_names()? {
+                println!("{name}");
+            }
             Ok(())
         }
     }
 }
 
+fn display(c: &DisplayCommand) -> Result<(), LibError> {
+    let p = file::load_playlist(&PathBuf::from(&c.playlist))?;
+    let mut indices = match &c.filter {
+        Some(filter) => p.matching(filter),
+        None => (0..p.song_count()).collect(),
+    };
+    if let Some(key) = c.sort {
+        p.sort_indices(&mut indices, key);
+    }
+    let durations = c
+        .with_duration
+        .then(|| song_durations(&p, &indices.iter().copied().collect()));
+    println!(
+        "{}",
+        Filtered {
+            playlist: &p,
+            indices,
+            durations,
+            filtered: c.filter.is_some(),
+            plays: c.sort == Some(SortKey::Plays),
+        }
+    );
+    if c.stats {
+        println!("  Stats:\n{}", p.stats());
+    }
+    Ok(())
+}
+
+///Stored durations of all songs, only the `shown` ones are decoded if unknown
+fn song_durations(p: &Playlist, shown: &HashSet<usize>) -> Vec<Option<Duration>> {
+    (0..p.song_count())
+        .filter_map(|i| p.song(i))
+        .enumerate()
+        .map(|(i, s)| {
+            s.duration.or_else(|| {
+                shown
+                    .contains(&i)
+                    .then(|| audio::duration(&s.path))
+                    .flatten()
+            })
+        })
+        .collect()
+}
+
+///`interactive` is whether the user can be asked before changes that lose songs
+fn edit(c: EditCommand, interactive: bool) -> Result<(), LibError> {
+    let path = &PathBuf::from(&c.playlist);
+    if c.undo {
+        return file::restore_backup(path);
+    }
+    let p = match file::load_playlist(path) {
+        Ok(p) => p,
+        Err(_) if !path.exists() => Playlist::new(),
+        Err(e) => {
+            if !c.dry_run {
+                let warning = format!(
+                    "{} would be replaced, it is no valid playlist: {e}",
+                    path.display()
+                );
+                confirm_overwrite(&warning, c.force, interactive)?;
+            }
+            Playlist::new()
+        }
+    };
+    if c.clear && !c.dry_run && p.song_count() > 0 {
+        let warning = format!(
+            "All {} songs would be removed from {}",
+            p.song_count(),
+            path.display()
+        );
+        confirm_overwrite(&warning, c.force, interactive)?;
+    }
+    let dry_run = c.dry_run;
+    let p = edit_playlist(p, c)?;
+    if dry_run {
+        println!("{p}");
+    } else {
+        file::backup_playlist(path)?;
+        file::save_playlist(&p, path)?;
+    }
+    Ok(())
+}
+
+///Go on only if forced or the user agrees to `warning`
+fn confirm_overwrite(warning: &str, force: bool, interactive: bool) -> Result<(), LibError> {
+    if force {
+        return Ok(());
+    }
+    if !interactive {
+        return Err(LibError::InvalidArgument(format!(
+            "{warning}. Use --force to do it anyway."
+        )));
+    }
+    eprint!("{warning}. Continue? [y/N] ");
+    let mut answer = String::new();
+    io::stdin()
+        .read_line(&mut answer)
+        .map_err(|e| LibError::Io("Error reading answer", e))?;
+    if matches!(answer.trim(), "y" | "Y" | "yes") {
+        Ok(())
+    } else {
+        Err(LibError::Aborted)
+    }
+}
+
 fn edit_playlist(mut p: Playlist, c: EditCommand) -> Result<Playlist, LibError> {
+    if c.clear {
+        p.clear();
+    }
+    if let Some(i) = c.remove {
+        p.remove_song(i).map_err(LibError::InvalidArgument)?;
+    }
+    if let Some(m) = c.move_song {
+        p.move_song(m[0], m[1]).map_err(LibError::InvalidArgument)?;
+    }
     if let Some(f) = c.file {
-        add_file_to_playlist(&mut p, Path::new(f.as_str()))?;
+        let options = DirectoryOptions {
+            recursive: c.recursive,
+            filter: !c.no_filter,
+        };
+        add_file_to_playlist(&mut p, Path::new(f.as_str()), options, c.absolute)?;
+    }
+    if let Some(f) = c.import {
+        add_songs_to_playlist(&mut p, file::load_m3u(Path::new(f.as_str()))?, c.absolute);
+    }
+    if let Some(f) = c.merge {
+        let other = file::load_playlist(&PathBuf::from(f))?;
+        if c.merge_config {
+            p.config = other.config.clone();
+        }
+        add_songs_to_playlist(&mut p, other.into_songs(), c.absolute);
+    }
+    if let Some(key) = c.sort {
+        p.sort_by(key);
+    }
+    if let Some(i) = c.song {
+        trim_song(&mut p, i, c.start, c.end)?;
+    }
+    if c.shuffle_now {
+        p.shuffle();
     }
     if let Some(a) = c.volume {
-        p.config.volume = a;
+        p.config.volume = check_volume(a)?;
+    }
+    if let Some(db) = c.volume_db {
+        p.config.volume = check_volume_db(db)?;
     }
     if let Some(r) = c.random {
         p.config.random = r;
     }
+    if let Some(f) = c.fade {
+        p.config.fade = parse_seconds(f)?;
+    }
+    if let Some(f) = c.crossfade {
+        p.config.crossfade = parse_seconds(f)?;
+    }
+    if let Some(g) = c.gap {
+        p.config.gap = parse_seconds(g)?;
+    }
+    if let Some(t) = c.tempo {
+        if !(0.25..=4.0).contains(&t) {
+            return Err(LibError::InvalidArgument(String::from(
+                "Tempo has to be between 0.25 and 4",
+            )));
+        }
+        p.config.tempo = Some(t).filter(|t| (t - 1.0).abs() > f32::EPSILON);
+        warn_tempo_unsupported(&p.config);
+    }
+    if let Some(l) = c.limiter {
+        p.config.limiter = l;
+    }
+    if let Some(r) = c.repeat {
+        p.config.repeat = r;
+    }
+    if let Some(min) = c.min_rating {
+        let min = check_rating(min)?;
+        p.validate_songs(|s| s.rated_at_least(min));
+    }
+    if c.analyze_loudness {
+        level_loudness(&mut p);
+    }
     if c.validate {
-        p = validate_playlist(p);
+        let jobs = c
+            .jobs
+            .or_else(|| thread::available_parallelism().ok())
+            .map_or(1, NonZeroUsize::get);
+        p = validate_playlist(p, c.thorough, jobs);
+    }
+    // After validating, so only valid songs are decoded
+    if c.scan {
+        scan_playlist(&mut p);
     }
     Ok(p)
 }
 
-fn play(c: &PlayCommand) -> Result<(), LibError> {
-    let state = prepare_play(c)?;
-    // These need to be created here so they won't be dropped until we are done playing,
-    // as Sink does not take ownership.
-    let (_stream, stream_handle) = match OutputStream::try_default() {
-        Ok(stream) => stream,
-        Err(e) => {
-            return Err(LibError(
-                String::from("Unable to create audio stream"),
-                Some(Box::new(e)),
-            ));
+///Volumes multiply, anything higher is most likely a mistake
+const MAX_VOLUME: f32 = 5.0;
+
+fn check_volume(volume: f32) -> Result<f32, LibError> {
+    if (0.0..=MAX_VOLUME).contains(&volume) {
+        Ok(volume)
+    } else {
+        Err(LibError::InvalidArgument(format!(
+            "Volume has to be between 0 and {MAX_VOLUME}, got {volume}"
+        )))
+    }
+}
+
+///Linear volume for `db` decibels
+fn check_volume_db(db: f32) -> Result<f32, LibError> {
+    let volume = audio::db_to_volume(db);
+    if volume <= MAX_VOLUME {
+        Ok(volume)
+    } else {
+        Err(LibError::InvalidArgument(format!(
+            "Volume has to be at most {:.1} dB, got {db} dB",
+            audio::volume_to_db(MAX_VOLUME)
+        )))
+    }
+}
+
+fn check_rating(rating: u8) -> Result<u8, LibError> {
+    if rating <= MAX_RATING {
+        Ok(rating)
+    } else {
+        Err(LibError::InvalidArgument(format!(
+            "Rating has to be between 0 and {MAX_RATING}, got {rating}"
+        )))
+    }
+}
+
+///The tempo is stored either way, so the playlist works with any build
+fn warn_tempo_unsupported(config: &PlaylistConfig) {
+    if config.tempo.is_some() && !cfg!(feature = "tempo") {
+        eprintln!("Tempo is ignored, rplaylist was built without the tempo feature");
+    }
+}
+
+///Start and end are given in seconds
+fn trim_song(
+    p: &mut Playlist, index: usize, start: Option<f32>, end: Option<f32>,
+) -> Result<(), LibError> {
+    let song = p
+        .song_mut(index)
+        .ok_or_else(|| LibError::InvalidArgument(format!("No song at index {index}")))?;
+    if let Some(s) = start {
+        song.config.start = parse_seconds(s)?;
+    }
+    if let Some(e) = end {
+        song.config.end = parse_seconds(e)?;
+    }
+    if let (Some(start), Some(end)) = (song.config.start, song.config.end) {
+        if end <= start {
+            return Err(LibError::InvalidArgument(String::from(
+                "End of song has to be after its start",
+            )));
         }
+    }
+    Ok(())
+}
+
+///The volume keys go up to `max`
+fn volume_steps(c: &PlayCommand, max: f32) -> Result<VolumeSteps, LibError> {
+    let mut steps = VolumeSteps {
+        linear: c.linear_volume,
+        max,
+        ..Default::default()
     };
-    let sink = match Sink::try_new(&stream_handle) {
-        Ok(s) => s,
-        Err(e) => {
-            return Err(LibError(
-                String::from("Unable to start audio stream"),
-                Some(Box::new(e)),
-            ));
+    if let Some(step) = c.volume_step {
+        // Taking away all of the volume couldn't be undone
+        let limit = if c.linear_volume { f32::MAX } else { 1.0 };
+        if !(step > 0.0 && step < limit) {
+            return Err(LibError::InvalidArgument(format!(
+                "Volume step has to be above 0{}",
+                if c.linear_volume { "" } else { " and below 1" }
+            )));
         }
-    };
-
-    let sink = Arc::new(sink);
-    let state = Arc::new(Mutex::new(state));
-
-    let (handle, tx) = controls::start(&sink, &state);
+        steps.step = step;
+    }
+    Ok(steps)
+}
 
-    play_playlist(&tx, &state, &sink, c.repeat);
+///Zero means not set
+fn parse_seconds(seconds: f32) -> Result<Option<Duration>, LibError> {
+    let duration = Duration::try_from_secs_f32(seconds)
+        .map_err(|e| LibError::InvalidArgument(format!("Invalid duration: {e}")))?;
+    Ok(Some(duration).filter(|d| !d.is_zero()))
+}
 
-    // Tell the controls we are done and wait for it to clean up.
-    let _ = tx.send(ControlMessage::StreamDone);
-    let result = handle
-        .join()
-        .map_err(|_| LibError::new(String::from("Controls crashed")));
+///Zero means not set, like `parse_seconds`
+fn parse_minutes(minutes: f32) -> Result<Option<Duration>, LibError> {
+    parse_seconds(minutes * 60.0)
+}
 
-    if result.is_ok() && state.lock().unwrap().control_error {
-        return Err(LibError::new(String::from("Playback aborted")));
+fn play(c: &PlayCommand, quiet: bool) -> Result<(), LibError> {
+    let mut state = prepare_play(c)?;
+    state.quiet = quiet;
+    if let Some(output) = &c.output {
+        return match render::render(&state, Path::new(output))? {
+            0 => Ok(()),
+            n => Err(LibError::DecodeFailed(format!(
+                "{n} songs could not be decoded and were left out"
+            ))),
+        };
     }
+    // Only the playlist as we started playing it, so we can tell whether it changed
+    let original = c.autosave.then(|| state.playlist.clone());
+    let player = Player::new(state);
+    let result = if c.validate_only {
+        player.play_silently()
+    } else {
+        let input = if c.commands {
+            Input::Commands
+        } else if !c.no_controls && !c.json && io::stdin().is_terminal() {
+            Input::Keys
+        } else {
+            Input::Off
+        };
+        player.run(c.device.as_deref(), input)
+    };
 
+    let state = player.state.lock().unwrap();
+    if let Some(path) = &state.resume_path {
+        // Played to the end, so there is nothing to resume next time
+        if result.is_ok() && !state.stopped() {
+            let _ = fs::remove_file(path);
+        }
+    }
+    if let (Some(original), Some(path)) = (original, &state.save_path) {
+        if state.playlist != original {
+            file::save_playlist(&state.playlist, path)?;
+        }
+    }
     result
 }
 
 fn prepare_play(c: &PlayCommand) -> Result<Playback, LibError> {
     let path = PathBuf::from(&c.file);
     let mut save_path = None;
-    let mut p = if c.playlist {
+    let mut p = if c.file == "-" {
+        if c.playlist || c.commands {
+            return Err(LibError::InvalidArgument(String::from(
+                "Only a list of songs can be read from stdin, no playlist or commands",
+            )));
+        }
+        let mut songs = file::read_song_list(io::stdin().lock())?;
+        metadata::tag_songs(&mut songs);
+        let mut p = Playlist::new();
+        p.add_songs(songs);
+        p
+    } else if c.playlist {
         save_path = Some(path.clone());
         file::load_playlist(&path)?
     } else {
-        file::make_playlist_from_path(&path)?
+        let options = DirectoryOptions {
+            recursive: c.recursive,
+            filter: !c.no_filter,
+        };
+        file::make_playlist_from_path(&path, options)?
     };
     if let Some(a) = c.volume {
-        p.config.volume = a;
+        p.config.volume = check_volume(a)?;
+    }
+    if let Some(db) = c.volume_db {
+        p.config.volume = check_volume_db(db)?;
+    }
+    if let Some(r) = &c.random {
+        p.config.random = r.clone();
     }
     if p.song_count() == 0 {
-        return Err(LibError::new(String::from("Playlist is empty")));
+        return Err(LibError::EmptyPlaylist);
     }
-    Ok(Playback::new(save_path, p))
-}
-
-fn play_playlist(tx: &Sender<ControlMessage>, state: &Mutex<Playback>, sink: &Sink, repeat: bool) {
-    if repeat {
-        while !state.lock().unwrap().stopped() {
-            if state.lock().unwrap().playlist.config.random == RandomMode::True {
-                play_true_random(tx, state, sink);
-            } else {
-                play_normal(tx, state, sink);
+    warn_tempo_unsupported(&p.config);
+    let skipped = skipped_songs(&p, c)?;
+    let mut playback = Playback::new(save_path.clone(), p);
+    playback.skipped = skipped;
+    if let Some(path) = save_path {
+        let path = file::bookmarks_path(&path);
+        if path.exists() {
+            match file::load_bookmarks(&path) {
+                Ok(b) => playback.bookmarks = b,
+                Err(e) => eprintln!("Unable to load bookmarks: {e}"),
             }
         }
-    } else {
-        play_normal(tx, state, sink);
+        playback.bookmarks_path = Some(path);
+    }
+    // Validating plays every song once
+    let repeat = c.repeat || playback.playlist.config.repeat;
+    playback.repeat = repeat && !c.no_repeat && !c.validate_only;
+    playback.repeat_one = c.repeat_one;
+    playback.limit = c.limit.map(NonZeroUsize::get);
+    if let Some(s) = c.preview {
+        playback.preview = parse_seconds(s)?;
+    }
+    playback.json = c.json;
+    if let Some(m) = c.sleep {
+        playback.sleep_at = parse_minutes(m)?.map(|d| Instant::now() + d);
+    }
+    if let Some(v) = c.max_volume {
+        playback.max_volume = check_volume(v)?;
+    }
+    playback.volume_steps = volume_steps(c, playback.max_volume)?;
+    if let Some(path) = &c.keymap {
+        playback.keymap = Keymap::load(Path::new(path))?;
     }
+    if let Some(path) = &c.socket {
+        if cfg!(not(unix)) {
+            return Err(LibError::InvalidArgument(String::from(
+                "Sockets are only supported on Unix",
+            )));
+        }
+        playback.socket = Some(PathBuf::from(path));
+    }
+    if let Some(s) = c.release_device {
+        playback.release_after = Some(parse_seconds(s)?.unwrap_or_default());
+    }
+    if c.resume {
+        let path = file::resume_path(&path);
+        if path.exists() {
+            match file::load_resume(&path) {
+                Ok(r) if r.index < playback.playlist.song_count() => playback.resume = Some(r),
+                Ok(_) => eprintln!("Unable to resume, the song is no longer in the playlist"),
+                Err(e) => eprintln!("Unable to resume: {e}"),
+            }
+        }
+        playback.resume_path = Some(path);
+    }
+    if let Some(index) = start_index(&playback.playlist, c)? {
+        playback.resume = Some(ResumePoint {
+            index,
+            position: Duration::ZERO,
+        });
+    }
+    Ok(playback)
 }
 
-fn play_normal(tx: &Sender<ControlMessage>, state: &Mutex<Playback>, sink: &Sink) {
-    let order = {
-        let playlist = &state.lock().unwrap().playlist;
-        let mut order: Vec<usize> = (0..playlist.song_count()).collect();
-
-        match playlist.config.random {
-            RandomMode::Off => (),
-            _ => order.shuffle(&mut rand::thread_rng()),
-        };
-
-        order
-    };
-
-    for song_index in order {
-        if state.lock().unwrap().stopped() {
-            break;
+///Songs left out as their files are missing or they are rated below `--min-rating`
+fn skipped_songs(p: &Playlist, c: &PlayCommand) -> Result<HashSet<usize>, LibError> {
+    let mut skipped = missing_songs(p, c.strict)?;
+    if let Some(min) = c.min_rating {
+        let min = check_rating(min)?;
+        skipped.extend((0..p.song_count()).filter(|&i| !p.song(i).unwrap().rated_at_least(min)));
+        if skipped.len() == p.song_count() {
+            return Err(LibError::InvalidArgument(format!(
+                "No song to play is rated {min} or higher"
+            )));
         }
-        play_song(tx, state, sink, song_index);
     }
+    Ok(skipped)
 }
 
-fn play_true_random(tx: &Sender<ControlMessage>, state: &Mutex<Playback>, sink: &Sink) {
-    let index = {
-        let state = state.lock().unwrap();
-        rand::thread_rng().gen_range(0..state.playlist.song_count())
-    };
-    play_song(tx, state, sink, index);
+///Indices of songs whose files don't exist, after warning about them.
+///Fails if all are missing, or any with `strict`.
+fn missing_songs(p: &Playlist, strict: bool) -> Result<HashSet<usize>, LibError> {
+    let missing: HashSet<usize> = (0..p.song_count())
+        .filter(|&i| !p.song(i).unwrap().path.exists())
+        .collect();
+    if missing.is_empty() {
+        return Ok(missing);
+    }
+    let mut paths: Vec<PathBuf> = (0..p.song_count())
+        .filter(|i| missing.contains(i))
+        .map(|i| p.song(i).unwrap().path.clone())
+        .collect();
+    if strict || missing.len() == p.song_count() {
+        return Err(LibError::MissingSongs(paths));
+    }
+    paths.truncate(10);
+    eprintln!(
+        "Skipping {} of {} songs, their files are missing: {}",
+        missing.len(),
+        p.song_count(),
+        display_paths(&paths)
+    );
+    Ok(missing)
 }
 
-fn play_song(tx: &Sender<ControlMessage>, state: &Mutex<Playback>, sink: &Sink, index: usize) {
-    let song;
-    let config;
-    {
-        let state = state.lock().unwrap();
-        song = state.playlist.song(index).unwrap().clone();
-        config = state.playlist.config.clone();
-    }
-    tx.send(ControlMessage::StartSong(index)).unwrap();
-
-    let file = File::open(&song.path);
-    match file {
-        Ok(file) => {
-            if let Err(LibError(msg, _)) = audio::play(file, sink, &song.config, &config) {
-                tx.send(ControlMessage::StreamError(msg)).unwrap();
-            }
+///Song to start with, given by `--start` or `--start-name`
+fn start_index(p: &Playlist, c: &PlayCommand) -> Result<Option<usize>, LibError> {
+    if let Some(index) = c.start {
+        if index >= p.song_count() {
+            return Err(LibError::InvalidArgument(format!(
+                "No song at index {index}, playlist has {} songs",
+                p.song_count()
+            )));
         }
-        Err(_) => tx
-            .send(ControlMessage::StreamError(String::from(
-                "Unable to open audio file",
-            )))
-            .unwrap(),
+        return Ok(Some(index));
     }
+    let Some(name) = &c.start_name else {
+        return Ok(None);
+    };
+    let lowercase = name.to_lowercase();
+    (0..p.song_count())
+        .find(|&i| {
+            p.song(i)
+                .unwrap()
+                .to_string()
+                .to_lowercase()
+                .contains(&lowercase)
+        })
+        .map(Some)
+        .ok_or_else(|| LibError::InvalidArgument(format!("No song name contains {name}")))
 }
 
-fn validate_playlist(mut p: Playlist) -> Playlist {
-    p.validate_songs(|song| {
-        let file = File::open(&song.path);
-        let valid = match file {
-            Ok(f) => audio::valid_audio_file(f),
-            Err(_) => false,
-        };
-        if !valid {
-            eprintln!("Filtered invalid audio file: {song}");
+///Only `thorough` validation opens the decoder, which also gives the duration.
+///Checks `jobs` songs at once, warnings are still printed in the order of the playlist.
+fn validate_playlist(mut p: Playlist, thorough: bool, jobs: usize) -> Playlist {
+    let paths: Vec<PathBuf> = (0..p.song_count())
+        .map(|i| p.song(i).unwrap().path.clone())
+        .collect();
+    let results = in_parallel(&paths, jobs, |path| {
+        if thorough {
+            audio::open(path).map(|decoder| Some(decoder.total_duration()))
+        } else {
+            audio::quick_check(path).map(|()| None)
+        }
+    });
+    let mut results = results.into_iter();
+    p.validate_songs(|song| match results.next().unwrap() {
+        Ok(duration) => {
+            if let Some(d) = duration {
+                song.duration = d;
+            }
+            true
+        }
+        Err(e) => {
+            eprintln!("Filtered invalid audio file {song}: {e}");
+            false
         }
-        valid
     });
     p
 }
 
-fn add_file_to_playlist(playlist: &mut Playlist, file: &Path) -> Result<(), LibError> {
-    let songs = file::load_songs(file)?;
-    for s in songs {
-        if let Err(e) = playlist.add_song(s) {
-            eprintln!("{e}");
+///`f` of every item, computed on up to `jobs` threads. Results are in the order of the items.
+///Each thread takes the next item once it is done, so a few slow items don't hold up the rest.
+fn in_parallel<T, R, F>(items: &[T], jobs: usize, f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<(usize, R)> = thread::scope(|s| {
+        let handles: Vec<_> = (0..jobs.clamp(1, items.len().max(1)))
+            .map(|_| {
+                s.spawn(|| {
+                    let mut done = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(item) = items.get(i) else {
+                            return done;
+                        };
+                        done.push((i, f(item)));
+                    }
+                })
+            })
+            .collect();
+        // Passes on a panic of `f`
+        handles
+            .into_iter()
+            .flat_map(|h| h.join().unwrap())
+            .collect()
+    });
+    results.sort_unstable_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, r)| r).collect()
+}
+
+///Set the volume of every song, so they all play about as loud
+fn level_loudness(p: &mut Playlist) {
+    for i in 0..p.song_count() {
+        let song = p.song_mut(i).unwrap();
+        let loudness = audio::open(&song.path)
+            .ok()
+            .and_then(|d| loudness::measure(audio::prepare(d, &song.config, None)));
+        match loudness {
+            Some(l) => song.config.volume = l.gain().min(MAX_VOLUME),
+            None => eprintln!("Unable to measure loudness of {song}"),
+        }
+    }
+}
+
+///Cache the duration of every song
+fn scan_playlist(p: &mut Playlist) {
+    for i in 0..p.song_count() {
+        let song = p.song_mut(i).unwrap();
+        song.duration = audio::duration(&song.path);
+        if let Some(tags) = metadata::read(&song.path) {
+            song.set_tags(tags);
+        }
+        if song.duration.is_none() {
+            eprintln!("Unable to get duration of {song}");
         }
     }
+}
+
+fn add_file_to_playlist(
+    playlist: &mut Playlist, file: &Path, options: DirectoryOptions, absolute: bool,
+) -> Result<(), LibError> {
+    add_songs_to_playlist(playlist, file::load_songs(file, options)?, absolute);
     Ok(())
 }
 
+///With `absolute`, the paths of the songs are made absolute, otherwise they are stored as given
+fn add_songs_to_playlist(playlist: &mut Playlist, mut songs: Vec<Song>, absolute: bool) {
+    if absolute {
+        for song in &mut songs {
+            song.path = file::absolute_path(&song.path);
+        }
+    }
+    metadata::tag_songs(&mut songs);
+    let (added, skipped) = playlist.add_songs(songs);
+    if skipped > 0 {
+        println!("Added {added}, skipped {skipped} duplicates");
+    } else {
+        println!("Added {added}");
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::playlist::Song;
-
     use super::*;
+    use crate::config::{RandomMode, SortKey};
 
     #[test]
     fn edit_no_change() {
-        let c = EditCommand {
-            volume: None,
-            file: None,
-            random: None,
-            playlist: String::new(),
-            validate: false,
-        };
+        let c = EditCommand::default();
 
         let mut p1 = Playlist::new();
         p1 = edit_playlist(p1, c).expect("Editing should give no error");
@@ -254,29 +787,76 @@ mod tests {
     #[test]
     fn valid_edit_amplify() {
         let c = EditCommand {
-            volume: Some(10.0),
-            file: None,
-            random: None,
-            playlist: String::new(),
-            validate: false,
+            volume: Some(5.0),
+            ..Default::default()
         };
 
         let mut p1 = Playlist::new();
         p1 = edit_playlist(p1, c).expect("Editing should give no error");
 
         let mut p2 = Playlist::new();
-        p2.config.volume = 10.0;
+        p2.config.volume = 5.0;
         assert_eq!(p1, p2);
     }
 
+    #[test]
+    fn invalid_edit_amplify_negative() -> Result<(), &'static str> {
+        let c = EditCommand {
+            volume: Some(-1.0),
+            ..Default::default()
+        };
+        match edit_playlist(Playlist::new(), c) {
+            Err(_) => Ok(()),
+            Ok(_) => Err("Negative volume should give error."),
+        }
+    }
+
+    #[test]
+    fn invalid_edit_amplify_nan() -> Result<(), &'static str> {
+        let c = EditCommand {
+            volume: Some(f32::NAN),
+            ..Default::default()
+        };
+        match edit_playlist(Playlist::new(), c) {
+            Err(_) => Ok(()),
+            Ok(_) => Err("NaN volume should give error."),
+        }
+    }
+
+    #[test]
+    fn valid_edit_fade() {
+        let c = EditCommand {
+            fade: Some(1.5),
+            ..Default::default()
+        };
+        let p = edit_playlist(Playlist::new(), c).expect("Editing should give no error");
+        assert_eq!(p.config.fade, Some(Duration::from_millis(1500)));
+
+        let c = EditCommand {
+            fade: Some(0.0),
+            ..Default::default()
+        };
+        let p = edit_playlist(p, c).expect("Editing should give no error");
+        assert_eq!(p.config.fade, None);
+    }
+
+    #[test]
+    fn invalid_edit_fade() -> Result<(), &'static str> {
+        let c = EditCommand {
+            fade: Some(-1.0),
+            ..Default::default()
+        };
+        match edit_playlist(Playlist::new(), c) {
+            Err(_) => Ok(()),
+            Ok(_) => Err("Negative fade should give error."),
+        }
+    }
+
     #[test]
     fn valid_edit_add_file() {
         let c = EditCommand {
-            volume: None,
             file: Some(String::from("test_data/test.mp3")),
-            random: None,
-            playlist: String::new(),
-            validate: false,
+            ..Default::default()
         };
 
         let mut p1 = Playlist::new();
@@ -288,31 +868,638 @@ mod tests {
         assert_eq!(p1, p2);
     }
 
+    #[test]
+    fn valid_edit_add_file_absolute() {
+        let c = EditCommand {
+            file: Some(String::from("test_data/test.mp3")),
+            absolute: true,
+            ..Default::default()
+        };
+
+        let p = edit_playlist(Playlist::new(), c).expect("Editing should give no error");
+        let path = &p.song(0).unwrap().path;
+        assert!(path.is_absolute());
+        assert_eq!(path, &fs::canonicalize("test_data/test.mp3").unwrap());
+    }
+
     #[test]
     fn invalid_edit_add_file() -> Result<(), &'static str> {
         let c = EditCommand {
-            volume: None,
             file: Some(String::from("invalid.mp3")),
-            random: None,
-            playlist: String::new(),
-            validate: false,
+            ..Default::default()
         };
 
         let p1 = Playlist::new();
         match edit_playlist(p1, c) {
-            Err(_) => Ok(()),
+            Err(LibError::FileNotFound(_)) => Ok(()),
+            Err(_) => Err("Invalid file should give FileNotFound."),
             Ok(_) => Err("Invalid file should give error."),
         }
     }
 
+    #[test]
+    fn edit_invalid_file_needs_force() {
+        let path = "test_invalid.playlist";
+        fs::write(path, "no playlist").unwrap();
+        let c = EditCommand {
+            playlist: String::from(path),
+            file: Some(String::from("test_data/test.mp3")),
+            ..Default::default()
+        };
+        let refused = edit(c, false);
+        let unchanged = fs::read_to_string(path).unwrap();
+        let c = EditCommand {
+            playlist: String::from(path),
+            file: Some(String::from("test_data/test.mp3")),
+            force: true,
+            ..Default::default()
+        };
+        let forced = edit(c, false);
+        let p = file::load_playlist(&PathBuf::from(path));
+        let backup = fs::read_to_string(file::backup_path(Path::new(path)));
+        fs::remove_file(path).unwrap();
+        fs::remove_file(file::backup_path(Path::new(path))).unwrap();
+
+        assert!(refused.is_err());
+        assert_eq!(unchanged, "no playlist");
+        assert_eq!(backup.unwrap(), "no playlist");
+        assert!(forced.is_ok());
+        assert_eq!(
+            p.expect("Forced edit should save a playlist").song_count(),
+            1
+        );
+    }
+
+    #[test]
+    fn edit_clear_needs_force() {
+        let path = "test_clear.playlist";
+        let c = EditCommand {
+            playlist: String::from(path),
+            file: Some(String::from("test_data/test.mp3")),
+            ..Default::default()
+        };
+        edit(c, false).expect("Creating a playlist should work");
+        let c = EditCommand {
+            playlist: String::from(path),
+            clear: true,
+            ..Default::default()
+        };
+        let refused = edit(c, false);
+        let c = EditCommand {
+            playlist: String::from(path),
+            clear: true,
+            force: true,
+            ..Default::default()
+        };
+        let forced = edit(c, false);
+        let p = file::load_playlist(&PathBuf::from(path));
+        fs::remove_file(path).unwrap();
+        fs::remove_file(file::backup_path(Path::new(path))).unwrap();
+
+        assert!(refused.is_err());
+        assert!(forced.is_ok());
+        assert_eq!(p.expect("Cleared playlist should load").song_count(), 0);
+    }
+
+    #[test]
+    fn edit_undo() {
+        let path = "test_undo.playlist";
+        let undo = || {
+            let c = EditCommand {
+                playlist: String::from(path),
+                undo: true,
+                ..Default::default()
+            };
+            edit(c, false)
+        };
+        let songs = || file::load_playlist(&PathBuf::from(path)).map(|p| p.song_count());
+        let nothing = undo();
+        let c = EditCommand {
+            playlist: String::from(path),
+            file: Some(String::from("test_data/test.mp3")),
+            ..Default::default()
+        };
+        edit(c, false).expect("Creating a playlist should work");
+        let c = EditCommand {
+            playlist: String::from(path),
+            clear: true,
+            force: true,
+            ..Default::default()
+        };
+        edit(c, false).expect("Clearing should work");
+        let cleared = songs();
+        let undone = undo().and_then(|()| songs());
+        let redone = undo().and_then(|()| songs());
+        fs::remove_file(path).unwrap();
+        fs::remove_file(file::backup_path(Path::new(path))).unwrap();
+
+        assert_eq!(nothing.unwrap_err().exit_code(), 3);
+        assert_eq!(cleared.unwrap(), 0);
+        assert_eq!(undone.expect("Undo should restore the song"), 1);
+        assert_eq!(redone.expect("Undoing again should clear it again"), 0);
+    }
+
+    #[test]
+    fn valid_edit_clear() {
+        let mut p = Playlist::new();
+        p.config.volume = 0.5;
+        p.config.random = RandomMode::True;
+        p.add_song(Song::new(PathBuf::from("test_data/test.mp3")))
+            .expect("Can always add a Song to an empty playlist");
+        let c = EditCommand {
+            clear: true,
+            ..Default::default()
+        };
+
+        let p1 = edit_playlist(p.clone(), c).expect("Editing should give no error");
+
+        assert_eq!(p1.song_count(), 0);
+        assert_eq!(p1.config, p.config);
+    }
+
+    #[test]
+    fn valid_edit_clear_then_add() {
+        let mut p = Playlist::new();
+        p.add_song(Song::new(PathBuf::from("test_data/nested/silence.wav")))
+            .expect("Can always add a Song to an empty playlist");
+        let c = EditCommand {
+            clear: true,
+            file: Some(String::from("test_data/test.mp3")),
+            ..Default::default()
+        };
+
+        let p1 = edit_playlist(p, c).expect("Editing should give no error");
+
+        assert_eq!(p1.song_count(), 1);
+        assert_eq!(p1.song(0).unwrap().path, Path::new("test_data/test.mp3"));
+    }
+
+    #[test]
+    fn valid_edit_add_duplicates() {
+        let mut p = Playlist::new();
+        p.add_song(Song::new(PathBuf::from("test_data/test.mp3")))
+            .expect("Can always add a Song to an empty playlist");
+        let songs = vec![
+            Song::new(PathBuf::from("test_data/test.mp3")),
+            Song::new(PathBuf::from("test_data/nested/silence.wav")),
+            Song::new(PathBuf::from("test_data/nested/silence.wav")),
+        ];
+
+        assert_eq!(p.add_songs(songs), (1, 2));
+        assert_eq!(p.song_count(), 2);
+    }
+
+    #[test]
+    fn valid_edit_add_same_file() {
+        let mut p = Playlist::new();
+        p.add_song(Song::new(PathBuf::from("test_data/test.mp3")))
+            .expect("Can always add a Song to an empty playlist");
+        let c = EditCommand {
+            file: Some(String::from("test_data/nested/../test.mp3")),
+            ..Default::default()
+        };
+
+        let p = edit_playlist(p, c).expect("Editing should give no error");
+
+        assert_eq!(p.song_count(), 1);
+    }
+
+    #[test]
+    fn valid_edit_merge() {
+        let mut p = Playlist::new();
+        p.add_song(Song::new(PathBuf::from("test_data/test.mp3")))
+            .expect("Can always add a Song to an empty playlist");
+        let c = EditCommand {
+            merge: Some(String::from("test_data/merge.playlist")),
+            ..Default::default()
+        };
+
+        let p1 = edit_playlist(p, c).expect("Editing should give no error");
+
+        assert_eq!(p1.song_count(), 2);
+        assert_eq!(p1.config, Playlist::new().config);
+    }
+
+    #[test]
+    fn valid_edit_merge_config() {
+        let c = EditCommand {
+            merge: Some(String::from("test_data/merge.playlist")),
+            merge_config: true,
+            ..Default::default()
+        };
+
+        let p = edit_playlist(Playlist::new(), c).expect("Editing should give no error");
+
+        assert_eq!(p.config.random, RandomMode::Shuffle);
+    }
+
+    #[test]
+    fn invalid_edit_merge() -> Result<(), &'static str> {
+        let c = EditCommand {
+            merge: Some(String::from("invalid.playlist")),
+            ..Default::default()
+        };
+        match edit_playlist(Playlist::new(), c) {
+            Err(LibError::Io(..)) => Ok(()),
+            Err(_) => Err("Missing playlist should give Io error."),
+            Ok(_) => Err("Missing playlist should give error."),
+        }
+    }
+
+    #[test]
+    fn valid_edit_sort() {
+        let mut p = Playlist::new();
+        for path in ["b/a.mp3", "a/c.mp3", "c/b.mp3"] {
+            p.add_song(Song::new(PathBuf::from(path))).unwrap();
+        }
+        let order = |p: &Playlist| -> Vec<PathBuf> {
+            (0..p.song_count())
+                .map(|i| p.song(i).unwrap().path.clone())
+                .collect()
+        };
+
+        let c = EditCommand {
+            sort: Some(SortKey::Name),
+            ..Default::default()
+        };
+        let p = edit_playlist(p, c).expect("Editing should give no error");
+        assert_eq!(
+            order(&p),
+            vec![
+                PathBuf::from("b/a.mp3"),
+                PathBuf::from("c/b.mp3"),
+                PathBuf::from("a/c.mp3")
+            ]
+        );
+
+        let c = EditCommand {
+            sort: Some(SortKey::Path),
+            ..Default::default()
+        };
+        let p = edit_playlist(p, c).expect("Editing should give no error");
+        assert_eq!(
+            order(&p),
+            vec![
+                PathBuf::from("a/c.mp3"),
+                PathBuf::from("b/a.mp3"),
+                PathBuf::from("c/b.mp3")
+            ]
+        );
+    }
+
+    #[test]
+    fn natural_edit_sort() {
+        let names = [
+            "10.mp3",
+            "9.mp3",
+            "track 2.mp3",
+            "Track 10.mp3",
+            "track 1.mp3",
+            "09.mp3",
+        ];
+        let mut p = Playlist::new();
+        for name in names {
+            p.add_song(Song::new(PathBuf::from(name))).unwrap();
+        }
+        let order = |p: &Playlist| -> Vec<String> {
+            (0..p.song_count())
+                .map(|i| p.song(i).unwrap().path.display().to_string())
+                .collect()
+        };
+
+        let c = EditCommand {
+            sort: Some(SortKey::Path),
+            ..Default::default()
+        };
+        let p = edit_playlist(p, c).expect("Editing should give no error");
+        assert_eq!(
+            order(&p),
+            [
+                "09.mp3",
+                "10.mp3",
+                "9.mp3",
+                "Track 10.mp3",
+                "track 1.mp3",
+                "track 2.mp3"
+            ]
+        );
+
+        let c = EditCommand {
+            sort: Some(SortKey::Natural),
+            ..Default::default()
+        };
+        let p = edit_playlist(p, c).expect("Editing should give no error");
+        assert_eq!(
+            order(&p),
+            [
+                "09.mp3",
+                "9.mp3",
+                "10.mp3",
+                "track 1.mp3",
+                "track 2.mp3",
+                "Track 10.mp3"
+            ]
+        );
+    }
+
+    #[test]
+    fn sort_by_plays() {
+        let mut p = Playlist::new();
+        let now = std::time::SystemTime::now();
+        for (path, plays, last) in [
+            ("never.mp3", 0, None),
+            ("earlier.mp3", 2, Some(now - Duration::from_hours(72))),
+            ("most.mp3", 5, Some(now - Duration::from_hours(24))),
+            ("later.mp3", 2, Some(now)),
+        ] {
+            let mut song = Song::new(PathBuf::from(path));
+            song.play_count = plays;
+            song.last_played = last;
+            p.add_song(song).unwrap();
+        }
+
+        let mut indices: Vec<usize> = (0..4).collect();
+        p.sort_indices(&mut indices, SortKey::Plays);
+        assert_eq!(indices, vec![2, 3, 1, 0]);
+        let shown = Filtered {
+            playlist: &p,
+            indices,
+            durations: None,
+            filtered: false,
+            plays: true,
+        }
+        .to_string();
+        assert!(shown.contains(
+            "[2] most.mp3 (5 plays, last yesterday)\n[3] later.mp3 (2 plays, last today)"
+        ));
+        assert!(shown
+            .contains("[1] earlier.mp3 (2 plays, last 3 days ago)\n[0] never.mp3 (not played)"));
+        assert!(!shown.contains("match"));
+    }
+
+    #[test]
+    fn play_random_overrides_config() {
+        let c = PlayCommand {
+            file: String::from("test_data/merge.playlist"),
+            playlist: true,
+            random: Some(RandomMode::True),
+            ..Default::default()
+        };
+        let playback = prepare_play(&c).expect("Playlist is valid");
+        assert_eq!(playback.playlist.config.random, RandomMode::True);
+    }
+
+    #[test]
+    fn play_start() {
+        let mut c = PlayCommand {
+            file: String::from("test_data/merge.playlist"),
+            playlist: true,
+            start: Some(1),
+            ..Default::default()
+        };
+        let playback = prepare_play(&c).expect("Playlist is valid");
+        assert_eq!(playback.resume.map(|r| r.index), Some(1));
+
+        c.start = None;
+        c.start_name = Some(String::from("SILENCE"));
+        let playback = prepare_play(&c).expect("Playlist is valid");
+        assert_eq!(playback.resume.map(|r| r.index), Some(1));
+    }
+
+    #[test]
+    fn invalid_play_start() -> Result<(), &'static str> {
+        let mut c = PlayCommand {
+            file: String::from("test_data/merge.playlist"),
+            playlist: true,
+            start: Some(2),
+            ..Default::default()
+        };
+        match prepare_play(&c) {
+            Err(LibError::InvalidArgument(_)) => (),
+            _ => return Err("Out of range index should give error."),
+        }
+        c.start = None;
+        c.start_name = Some(String::from("missing"));
+        match prepare_play(&c) {
+            Err(LibError::InvalidArgument(_)) => Ok(()),
+            _ => Err("Unknown name should give error."),
+        }
+    }
+
+    #[test]
+    fn edit_min_rating() {
+        let mut p = Playlist::new();
+        for (path, rating) in [("a.mp3", None), ("b.mp3", Some(3)), ("c.mp3", Some(5))] {
+            let mut song = Song::new(PathBuf::from(path));
+            song.rating = rating;
+            p.add_song(song).unwrap();
+        }
+        let c = EditCommand {
+            min_rating: Some(6),
+            ..Default::default()
+        };
+        assert!(edit_playlist(p.clone(), c).is_err());
+
+        let c = EditCommand {
+            min_rating: Some(3),
+            ..Default::default()
+        };
+        let p = edit_playlist(p, c).expect("Editing should give no error");
+        assert_eq!(p.song_count(), 2);
+        assert_eq!(p.song(0).unwrap().path, PathBuf::from("b.mp3"));
+    }
+
+    #[test]
+    fn play_min_rating() {
+        let mut c = PlayCommand {
+            file: String::from("test_data/merge.playlist"),
+            playlist: true,
+            min_rating: Some(0),
+            ..Default::default()
+        };
+        let playback = prepare_play(&c).expect("Unrated songs count as 0");
+        assert!(playback.skipped.is_empty());
+        c.min_rating = Some(1);
+        assert!(prepare_play(&c).is_err());
+    }
+
+    #[test]
+    fn exit_codes() {
+        let missing = file::load_playlist(&PathBuf::from("test_data/missing.playlist"));
+        assert_eq!(missing.unwrap_err().exit_code(), 3);
+        let c = PlayCommand {
+            file: String::from("test_data/empty.playlist"),
+            playlist: true,
+            ..Default::default()
+        };
+        assert_eq!(prepare_play(&c).err().map(|e| e.exit_code()), Some(5));
+        let c = PlayCommand {
+            file: String::from("test_data/merge.playlist"),
+            playlist: true,
+            start: Some(5),
+            ..Default::default()
+        };
+        assert_eq!(prepare_play(&c).err().map(|e| e.exit_code()), Some(2));
+        assert_eq!(LibError::Aborted.exit_code(), 7);
+    }
+
+    #[test]
+    fn edit_volume_db() {
+        let c = EditCommand {
+            volume_db: Some(-6.0),
+            ..Default::default()
+        };
+        let p = edit_playlist(Playlist::new(), c).expect("Editing should give no error");
+        assert!((p.config.volume - 0.5).abs() < 0.01);
+        let c = EditCommand {
+            volume_db: Some(15.0),
+            ..Default::default()
+        };
+        assert!(edit_playlist(Playlist::new(), c).is_err());
+    }
+
+    #[test]
+    fn valid_edit_gap() {
+        let c = EditCommand {
+            gap: Some(2.0),
+            ..Default::default()
+        };
+        let p = edit_playlist(Playlist::new(), c).expect("Editing should give no error");
+        assert_eq!(p.config.gap, Some(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn skip_missing_songs() -> Result<(), &'static str> {
+        let mut p = Playlist::new();
+        p.add_song(Song::new(PathBuf::from("test_data/test.mp3")))
+            .unwrap();
+        p.add_song(Song::new(PathBuf::from("test_data/missing.mp3")))
+            .unwrap();
+
+        let missing = missing_songs(&p, false).expect("Only some songs are missing");
+        assert_eq!(missing, HashSet::from([1]));
+        match missing_songs(&p, true) {
+            Err(LibError::MissingSongs(paths)) => {
+                assert_eq!(paths, vec![PathBuf::from("test_data/missing.mp3")]);
+            }
+            _ => return Err("Strict should fail on a missing song."),
+        }
+        p.remove_song(0).unwrap();
+        match missing_songs(&p, false) {
+            Err(LibError::MissingSongs(_)) => Ok(()),
+            _ => Err("All songs missing should give error."),
+        }
+    }
+
+    #[test]
+    fn playlist_matching() {
+        let mut p = Playlist::new();
+        p.add_song(Song::new(PathBuf::from("a/Intro.mp3"))).unwrap();
+        let mut tagged = Song::new(PathBuf::from("a/02.mp3"));
+        tagged.artist = Some(String::from("The Band"));
+        p.add_song(tagged).unwrap();
+
+        assert_eq!(p.matching("intro"), vec![0]);
+        assert_eq!(p.matching("BAND"), vec![1]);
+        assert_eq!(p.matching(".mp3"), vec![0, 1]);
+        assert!(p.matching("missing").is_empty());
+    }
+
+    #[test]
+    fn playlist_stats() {
+        let mut p = Playlist::new();
+        let mut scanned = Song::new(PathBuf::from("a.mp3"));
+        scanned.duration = Some(Duration::from_secs(90));
+        p.add_song(scanned).unwrap();
+        p.add_song(Song::new(PathBuf::from("b.mp3"))).unwrap();
+
+        let stats = p.stats();
+        assert_eq!(stats.songs, 2);
+        assert_eq!(stats.duration, Duration::from_secs(90));
+        assert_eq!(stats.unscanned, 1);
+        assert_eq!(
+            stats.to_string(),
+            "2 songs, 01:30 (without 1 not scanned); Random mode: OFF; Amplify: 1"
+        );
+    }
+
+    #[test]
+    fn valid_edit_limiter() {
+        let c = EditCommand {
+            limiter: Some(true),
+            ..Default::default()
+        };
+        let p = edit_playlist(Playlist::new(), c).expect("Editing should give no error");
+        assert!(p.config.limiter);
+    }
+
+    #[test]
+    fn play_repeat_from_config() {
+        let mut p = Playlist::new();
+        p.add_song(Song::new(PathBuf::from("test_data/test.mp3")))
+            .unwrap();
+        let c = EditCommand {
+            repeat: Some(true),
+            ..Default::default()
+        };
+        let p = edit_playlist(p, c).expect("Editing should give no error");
+        assert!(p.config.repeat);
+        let path = PathBuf::from("test_repeat.playlist");
+        file::save_playlist(&p, &path).unwrap();
+
+        let mut c = PlayCommand {
+            file: String::from("test_repeat.playlist"),
+            playlist: true,
+            ..Default::default()
+        };
+        let repeat = prepare_play(&c).map(|p| p.repeat);
+        c.no_repeat = true;
+        let no_repeat = prepare_play(&c).map(|p| p.repeat);
+        fs::remove_file(path).unwrap();
+
+        assert!(repeat.expect("Playlist is valid"));
+        assert!(!no_repeat.expect("Playlist is valid"));
+        let c = PlayCommand {
+            file: String::from("test_data/merge.playlist"),
+            playlist: true,
+            repeat: true,
+            ..Default::default()
+        };
+        assert!(prepare_play(&c).expect("Playlist is valid").repeat);
+    }
+
+    #[test]
+    #[cfg(feature = "mp3")]
+    fn valid_edit_analyze_loudness() {
+        let mut p = Playlist::new();
+        p.add_song(Song::new(PathBuf::from("test_data/test.mp3")))
+            .expect("Can always add a Song to an empty playlist");
+        let c = EditCommand {
+            analyze_loudness: true,
+            ..Default::default()
+        };
+
+        let p = edit_playlist(p, c).expect("Editing should give no error");
+
+        let volume = p.song(0).unwrap().config.volume;
+        assert!(volume > 0.0 && (volume - 1.0).abs() > f32::EPSILON);
+    }
+
+    #[test]
+    fn valid_edit_import() {
+        let c = EditCommand {
+            import: Some(String::from("test_data/test.m3u")),
+            ..Default::default()
+        };
+        let p = edit_playlist(Playlist::new(), c).expect("Editing should give no error");
+        assert_eq!(p.song_count(), 2);
+    }
+
     #[test]
     fn filter_invalid_not_existing() {
         let c = EditCommand {
-            volume: None,
-            file: None,
-            random: None,
-            playlist: String::new(),
             validate: true,
+            ..Default::default()
         };
         let mut p = Playlist::new();
         p.add_song(Song::new(PathBuf::from("file.invalid")))
@@ -324,11 +1511,8 @@ mod tests {
     #[test]
     fn filter_invalid_not_audio() {
         let c = EditCommand {
-            volume: None,
-            file: None,
-            random: None,
-            playlist: String::new(),
             validate: true,
+            ..Default::default()
         };
         let mut p = Playlist::new();
         p.add_song(Song::new(PathBuf::from("test_data/empty.playlist")))
@@ -338,18 +1522,191 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "mp3")]
     fn filter_invalid_valid() {
         let c = EditCommand {
-            volume: None,
-            file: None,
-            random: None,
-            playlist: String::new(),
             validate: true,
+            ..Default::default()
+        };
+        let mut p = Playlist::new();
+        p.add_song(Song::new(PathBuf::from("test_data/test.mp3")))
+            .unwrap();
+        p = edit_playlist(p, c).expect("Editing should give no error");
+        assert_eq!(p.song_count(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "mp3")]
+    fn filter_invalid_thorough() {
+        let c = EditCommand {
+            validate: true,
+            thorough: true,
+            ..Default::default()
+        };
+        let mut p = Playlist::new();
+        p.add_song(Song::new(PathBuf::from("test_data/test.mp3")))
+            .unwrap();
+        p.add_song(Song::new(PathBuf::from("test_data/empty.playlist")))
+            .unwrap();
+        p = edit_playlist(p, c).expect("Editing should give no error");
+        assert_eq!(p.song_count(), 1);
+        assert!(p.song(0).unwrap().duration.is_some());
+    }
+
+    #[test]
+    fn in_parallel_keeps_order() {
+        let items: Vec<u32> = (0..100).collect();
+        for jobs in [1, 3, 8, 200] {
+            let squares = in_parallel(&items, jobs, |i| i * i);
+            assert_eq!(squares, items.iter().map(|i| i * i).collect::<Vec<_>>());
+        }
+        assert!(in_parallel(&[] as &[u32], 4, |i| *i).is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "mp3")]
+    fn valid_edit_scan() {
+        let c = EditCommand {
+            scan: true,
+            ..Default::default()
+        };
+        let mut p = Playlist::new();
+        p.add_song(Song::new(PathBuf::from("test_data/test.mp3")))
+            .unwrap();
+        p.add_song(Song::new(PathBuf::from("test_data/not_existing.mp3")))
+            .unwrap();
+        p = edit_playlist(p, c).expect("Editing should give no error");
+        let duration = p.song(0).unwrap().duration.map(|d| d.as_secs());
+        assert_eq!(duration, Some(3));
+        assert_eq!(p.song(1).unwrap().duration, None);
+    }
+
+    #[test]
+    #[cfg(feature = "mp3")]
+    fn valid_edit_validate_scan() {
+        let c = EditCommand {
+            validate: true,
+            scan: true,
+            ..Default::default()
         };
         let mut p = Playlist::new();
         p.add_song(Song::new(PathBuf::from("test_data/test.mp3")))
             .unwrap();
+        p.add_song(Song::new(PathBuf::from("test_data/empty.playlist")))
+            .unwrap();
+        p = edit_playlist(p, c).expect("Editing should give no error");
+        assert_eq!(p.song_count(), 1);
+        let duration = p.song(0).unwrap().duration.map(|d| d.as_secs());
+        assert_eq!(duration, Some(3));
+    }
+
+    #[test]
+    fn valid_edit_remove() {
+        let c = EditCommand {
+            remove: Some(0),
+            ..Default::default()
+        };
+        let mut p = Playlist::new();
+        p.add_song(Song::new(PathBuf::from("a.mp3"))).unwrap();
+        p.add_song(Song::new(PathBuf::from("b.mp3"))).unwrap();
         p = edit_playlist(p, c).expect("Editing should give no error");
         assert_eq!(p.song_count(), 1);
+        assert_eq!(p.song(0).unwrap().path, PathBuf::from("b.mp3"));
+    }
+
+    #[test]
+    fn invalid_edit_remove() -> Result<(), &'static str> {
+        let c = EditCommand {
+            remove: Some(1),
+            ..Default::default()
+        };
+        let mut p = Playlist::new();
+        p.add_song(Song::new(PathBuf::from("a.mp3"))).unwrap();
+        match edit_playlist(p, c) {
+            Err(LibError::InvalidArgument(_)) => Ok(()),
+            Err(_) => Err("Removing out of range index should give InvalidArgument."),
+            Ok(_) => Err("Removing out of range index should give error."),
+        }
+    }
+
+    #[test]
+    fn valid_edit_move() {
+        let c = EditCommand {
+            move_song: Some(vec![0, 2]),
+            ..Default::default()
+        };
+        let mut p = Playlist::new();
+        p.add_song(Song::new(PathBuf::from("a.mp3"))).unwrap();
+        p.add_song(Song::new(PathBuf::from("b.mp3"))).unwrap();
+        p.add_song(Song::new(PathBuf::from("c.mp3"))).unwrap();
+        p = edit_playlist(p, c).expect("Editing should give no error");
+        assert_eq!(p.song(0).unwrap().path, PathBuf::from("b.mp3"));
+        assert_eq!(p.song(1).unwrap().path, PathBuf::from("c.mp3"));
+        assert_eq!(p.song(2).unwrap().path, PathBuf::from("a.mp3"));
+    }
+
+    #[test]
+    fn valid_edit_shuffle_now() {
+        let c = EditCommand {
+            shuffle_now: true,
+            ..Default::default()
+        };
+        let mut p = Playlist::new();
+        for i in 0..20 {
+            p.add_song(Song::new(PathBuf::from(format!("{i}.mp3"))))
+                .unwrap();
+        }
+        let p = edit_playlist(p, c).expect("Editing should give no error");
+        assert_eq!(p.song_count(), 20);
+        for i in 0..20 {
+            let path = PathBuf::from(format!("{i}.mp3"));
+            assert!((0..20).any(|j| p.song(j).unwrap().path == path));
+        }
+    }
+
+    #[test]
+    fn valid_edit_trim() {
+        let c = EditCommand {
+            song: Some(0),
+            start: Some(1.5),
+            end: Some(10.0),
+            ..Default::default()
+        };
+        let mut p = Playlist::new();
+        p.add_song(Song::new(PathBuf::from("a.mp3"))).unwrap();
+        let p = edit_playlist(p, c).expect("Editing should give no error");
+        let config = &p.song(0).unwrap().config;
+        assert_eq!(config.start, Some(Duration::from_millis(1500)));
+        assert_eq!(config.end, Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn invalid_edit_trim() -> Result<(), &'static str> {
+        let c = EditCommand {
+            song: Some(0),
+            start: Some(10.0),
+            end: Some(5.0),
+            ..Default::default()
+        };
+        let mut p = Playlist::new();
+        p.add_song(Song::new(PathBuf::from("a.mp3"))).unwrap();
+        match edit_playlist(p, c) {
+            Err(_) => Ok(()),
+            Ok(_) => Err("End before start should give error."),
+        }
+    }
+
+    #[test]
+    fn invalid_edit_move() -> Result<(), &'static str> {
+        let c = EditCommand {
+            move_song: Some(vec![0, 1]),
+            ..Default::default()
+        };
+        let mut p = Playlist::new();
+        p.add_song(Song::new(PathBuf::from("a.mp3"))).unwrap();
+        match edit_playlist(p, c) {
+            Err(_) => Ok(()),
+            Ok(_) => Err("Moving out of range should give error."),
+        }
     }
 }
@@ -0,0 +1,181 @@
+use std::f64::consts::PI;
+
+use rodio::Source;
+
+///Loudness songs are leveled to, in LUFS. The same as Replay Gain 2.0 uses.
+pub const TARGET: f64 = -18.0;
+
+///Blocks are 400ms long and overlap by 75%, so they are made of four 100ms steps
+const STEPS_PER_BLOCK: usize = 4;
+///Blocks quieter than this are silence, in LUFS
+const ABSOLUTE_GATE: f64 = -70.0;
+///Blocks this much quieter than the average are ignored, in LU
+const RELATIVE_GATE: f64 = 10.0;
+
+pub struct Loudness {
+    ///Integrated loudness (EBU R128) in LUFS
+    pub integrated: f64,
+    ///Highest absolute sample value, 1 is full scale
+    pub peak: f64,
+}
+
+impl Loudness {
+    ///Volume factor that levels the song to `TARGET`, without clipping
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn gain(&self) -> f32 {
+        let gain = 10f64.powf((TARGET - self.integrated) / 20.0);
+        if self.peak > 0.0 {
+            gain.min(1.0 / self.peak) as f32
+        } else {
+            gain as f32
+        }
+    }
+}
+
+///Biquad filter, in direct form I
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+///The K-weighting of ITU-R BS.1770: a high shelf modelling the head, then a high pass.
+///Coefficients are derived for any sample rate, they match the ones given for 48kHz.
+fn k_weighting(rate: u32) -> [Biquad; 2] {
+    let rate = f64::from(rate);
+
+    let k = (PI * 1_681.974_450_955_533 / rate).tan();
+    let q = 0.707_175_236_955_419_6;
+    let vh = 10f64.powf(3.999_843_853_973_347 / 20.0);
+    let vb = vh.powf(0.499_666_774_154_541_6);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        x: [0.0; 2],
+        y: [0.0; 2],
+    };
+
+    let k = (PI * 38.135_470_876_024_44 / rate).tan();
+    let q = 0.500_327_037_323_877_3;
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        x: [0.0; 2],
+        y: [0.0; 2],
+    };
+
+    [shelf, high_pass]
+}
+
+///Surround channels of 5.1 count more, the LFE channel not at all
+fn channel_weight(channels: usize, channel: usize) -> f64 {
+    match (channels, channel) {
+        (6, 3) => 0.0,
+        (6, 4 | 5) => 1.41,
+        _ => 1.0,
+    }
+}
+
+fn to_lufs(power: f64) -> f64 {
+    -0.691 + 10.0 * power.log10()
+}
+
+///Decode the whole source to measure it. None if it is too short or silent.
+#[allow(clippy::cast_precision_loss)]
+pub fn measure<S>(source: S) -> Option<Loudness>
+where
+    S: Source<Item = i16>,
+{
+    let channels = usize::from(source.channels().max(1));
+    let step = (source.sample_rate() / 10).max(1) as usize;
+    let mut filters: Vec<[Biquad; 2]> = (0..channels)
+        .map(|_| k_weighting(source.sample_rate()))
+        .collect();
+
+    // Weighted mean square of every 100ms step
+    let mut steps = vec![];
+    let mut sum = 0.0;
+    let mut frame_samples = 0;
+    let mut peak: f64 = 0.0;
+    for (i, s) in source.enumerate() {
+        let channel = i % channels;
+        let x = f64::from(s) / 32768.0;
+        peak = peak.max(x.abs());
+        let [shelf, high_pass] = &mut filters[channel];
+        let y = high_pass.process(shelf.process(x));
+        sum += channel_weight(channels, channel) * y * y;
+        if channel == channels - 1 {
+            frame_samples += 1;
+            if frame_samples == step {
+                steps.push(sum / step as f64);
+                sum = 0.0;
+                frame_samples = 0;
+            }
+        }
+    }
+
+    let blocks: Vec<f64> = steps
+        .windows(STEPS_PER_BLOCK)
+        .map(|w| w.iter().sum::<f64>() / STEPS_PER_BLOCK as f64)
+        .filter(|&p| to_lufs(p) > ABSOLUTE_GATE)
+        .collect();
+    if blocks.is_empty() {
+        return None;
+    }
+    let mean = |blocks: &[f64]| blocks.iter().sum::<f64>() / blocks.len() as f64;
+    let relative_gate = to_lufs(mean(&blocks)) - RELATIVE_GATE;
+    let gated: Vec<f64> = blocks
+        .into_iter()
+        .filter(|&p| to_lufs(p) > relative_gate)
+        .collect();
+
+    Some(Loudness {
+        integrated: to_lufs(mean(&gated)),
+        peak,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rodio::source::SineWave;
+
+    use super::*;
+
+    #[test]
+    fn full_scale_sine() {
+        // BS.1770 defines a full scale 997Hz sine on one channel as -3.01 LUFS
+        let sine = SineWave::new(997.0)
+            .take_duration(Duration::from_secs(3))
+            .convert_samples::<i16>();
+        let loudness = measure(sine).expect("Sine is neither short nor silent");
+        assert!((loudness.integrated + 3.01).abs() < 0.05);
+        assert!(loudness.peak > 0.99);
+    }
+
+    #[test]
+    fn silence() {
+        let silence =
+            rodio::source::Zero::<i16>::new(2, 44_100).take_duration(Duration::from_secs(1));
+        assert!(measure(silence).is_none());
+    }
+}
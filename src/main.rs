@@ -1,3 +1,4 @@
+use std::error::Error;
 use std::process::ExitCode;
 
 use clap::Parser;
@@ -6,15 +7,19 @@ use rplaylist::config::Cli;
 
 fn main() -> ExitCode {
     let cli = Cli::parse();
+    let verbose = cli.verbose;
 
-    // Disable for debugging to show exact panic message
-    if true {
-        if let Err(e) = rplaylist::run(cli) {
-            eprintln!("{e}");
-            return ExitCode::from(1);
+    if let Err(e) = rplaylist::run(cli) {
+        eprintln!("{e}");
+        if verbose {
+            // The message already includes the direct source
+            let mut source = e.source().and_then(Error::source);
+            while let Some(cause) = source {
+                eprintln!("Caused by: {cause}");
+                source = cause.source();
+            }
         }
-    } else {
-        rplaylist::run(cli).expect("The heck?");
+        return ExitCode::from(e.exit_code());
     }
     ExitCode::from(0)
 }
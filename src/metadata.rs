@@ -0,0 +1,99 @@
+use std::ffi::OsStr;
+use std::fs::File;
+use std::path::Path;
+
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::{MediaSourceStream, MediaSourceStreamOptions};
+use symphonia::core::meta::{MetadataOptions, MetadataRevision, StandardTagKey};
+use symphonia::core::probe::Hint;
+
+use crate::playlist::Song;
+
+///Tags stored in the file, like ID3 or Vorbis comments
+#[derive(Debug, Default, PartialEq)]
+pub struct Tags {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+}
+
+impl Tags {
+    ///Tags found first win, so tags in front of the file go before those of the container
+    fn add(&mut self, revision: &MetadataRevision) {
+        for tag in revision.tags() {
+            let field = match tag.std_key {
+                Some(StandardTagKey::TrackTitle) => &mut self.title,
+                Some(StandardTagKey::Artist) => &mut self.artist,
+                Some(StandardTagKey::Album) => &mut self.album,
+                _ => continue,
+            };
+            let value = tag.value.to_string();
+            if field.is_none() && !value.trim().is_empty() {
+                *field = Some(value.trim().to_string());
+            }
+        }
+    }
+}
+
+///Only reads the start of the file. None if it is no audio file this build can read.
+pub fn read(path: &Path) -> Option<Tags> {
+    let file = File::open(path).ok()?;
+    let stream = MediaSourceStream::new(Box::new(file), MediaSourceStreamOptions::default());
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(OsStr::to_str) {
+        hint.with_extension(extension);
+    }
+    let mut probed = symphonia::default::get_probe()
+        .format(
+            &hint,
+            stream,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .ok()?;
+
+    let mut tags = Tags::default();
+    if let Some(revision) = probed.metadata.get().as_ref().and_then(|m| m.current()) {
+        tags.add(revision);
+    }
+    if let Some(revision) = probed.format.metadata().current() {
+        tags.add(revision);
+    }
+    Some(tags)
+}
+
+///Store the tags of songs that have none yet
+pub fn tag_songs(songs: &mut [Song]) {
+    for song in songs.iter_mut().filter(|s| !s.has_tags()) {
+        if let Some(tags) = read(&song.path) {
+            song.set_tags(tags);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "mp3")]
+    fn read_untagged() {
+        assert_eq!(read(Path::new("test_data/test.mp3")), Some(Tags::default()));
+        assert_eq!(read(Path::new("test_data/empty.playlist")), None);
+    }
+
+    #[test]
+    fn display_tags() {
+        let mut song = Song::new(PathBuf::from("music/01.mp3"));
+        assert_eq!(song.to_string(), "01.mp3");
+        song.set_tags(Tags {
+            title: Some(String::from("Title")),
+            ..Default::default()
+        });
+        assert_eq!(song.to_string(), "Title");
+        song.artist = Some(String::from("Artist"));
+        assert_eq!(song.to_string(), "Artist - Title");
+    }
+}
@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use zbus::blocking::connection;
+use zbus::blocking::object_server::InterfaceRef;
+use zbus::zvariant::{ObjectPath, OwnedValue, Value};
+use zbus::{fdo, interface};
+
+use crate::audio::AudioSink;
+use crate::controls::{self, Playback};
+
+const PATH: &str = "/org/mpris/MediaPlayer2";
+const NO_TRACK: &str = "/org/mpris/MediaPlayer2/TrackList/NoTrack";
+
+///Media keys and desktop media controls through MPRIS on the D-Bus session bus.
+///Changes are only announced on `update`.
+pub struct Media {
+    player: InterfaceRef<Player>,
+    ///Song, whether paused and volume at the last update
+    last: (Option<usize>, bool, f32),
+    // Unregisters once dropped
+    _connection: zbus::blocking::Connection,
+}
+
+impl Media {
+    pub fn connect(
+        sink: &Arc<dyn AudioSink>, playback: &Arc<Mutex<Playback>>,
+    ) -> zbus::Result<Media> {
+        let root = Root {
+            sink: sink.clone(),
+            playback: playback.clone(),
+        };
+        let player = Player {
+            sink: sink.clone(),
+            playback: playback.clone(),
+        };
+        // Several instances can run at once, each needs its own name
+        let name = format!(
+            "org.mpris.MediaPlayer2.rplaylist.instance{}",
+            std::process::id()
+        );
+        let connection = connection::Builder::session()?
+            .name(name)?
+            .serve_at(PATH, root)?
+            .serve_at(PATH, player)?
+            .build()?;
+        let player = connection.object_server().interface::<_, Player>(PATH)?;
+        Ok(Media {
+            player,
+            last: (None, false, 0.0),
+            _connection: connection,
+        })
+    }
+
+    ///Announce the song, pause and volume if they changed since the last update
+    pub fn update(&mut self) -> zbus::Result<()> {
+        let player = self.player.get();
+        let current = {
+            let playback = player.playback.lock().unwrap();
+            (
+                playback.song,
+                player.sink.is_paused(),
+                playback.playlist.config.volume,
+            )
+        };
+        let context = self.player.signal_context();
+        if current.0 != self.last.0 {
+            zbus::block_on(player.metadata_changed(context))?;
+        }
+        if current.1 != self.last.1 || current.0.is_none() != self.last.0.is_none() {
+            zbus::block_on(player.playback_status_changed(context))?;
+        }
+        #[allow(clippy::float_cmp)]
+        if current.2 != self.last.2 {
+            zbus::block_on(player.volume_changed(context))?;
+        }
+        self.last = current;
+        Ok(())
+    }
+}
+
+struct Root {
+    sink: Arc<dyn AudioSink>,
+    playback: Arc<Mutex<Playback>>,
+}
+
+// D-Bus methods and properties always take self
+#[allow(clippy::unused_self)]
+#[interface(name = "org.mpris.MediaPlayer2")]
+impl Root {
+    fn raise(&self) {}
+
+    fn quit(&self) {
+        controls::stop_playback(&*self.sink, &self.playback);
+    }
+
+    #[zbus(property)]
+    fn can_quit(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_raise(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn has_track_list(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn identity(&self) -> &'static str {
+        "rplaylist"
+    }
+
+    #[zbus(property)]
+    fn supported_uri_schemes(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    #[zbus(property)]
+    fn supported_mime_types(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+struct Player {
+    sink: Arc<dyn AudioSink>,
+    playback: Arc<Mutex<Playback>>,
+}
+
+impl Player {
+    ///Seek to `position` microseconds into the current song
+    fn seek_to(&self, position: i64) {
+        let position = Duration::from_micros(position.try_into().unwrap_or_default());
+        if let Err(e) = self.sink.try_seek(position) {
+            eprintln!("Unable to seek: {e}");
+        }
+    }
+}
+
+#[allow(clippy::unused_self)]
+#[interface(name = "org.mpris.MediaPlayer2.Player")]
+impl Player {
+    fn next(&self) {
+        controls::next_song(&*self.sink, &self.playback);
+    }
+
+    fn previous(&self) {
+        controls::previous_song(&*self.sink, &self.playback);
+    }
+
+    fn pause(&self) {
+        self.sink.pause();
+    }
+
+    fn play_pause(&self) {
+        if self.sink.is_paused() {
+            self.sink.play();
+        } else {
+            self.sink.pause();
+        }
+    }
+
+    fn stop(&self) {
+        controls::stop_playback(&*self.sink, &self.playback);
+    }
+
+    fn play(&self) {
+        self.sink.play();
+    }
+
+    ///`offset` in microseconds, negative to seek back
+    fn seek(&self, offset: i64) {
+        let position = i64::try_from(self.sink.get_pos().as_micros()).unwrap_or(i64::MAX);
+        self.seek_to(position.saturating_add(offset));
+    }
+
+    ///Ignored if `track_id` is no longer the current song
+    #[allow(clippy::needless_pass_by_value)]
+    fn set_position(&self, track_id: ObjectPath<'_>, position: i64) {
+        let current = track_path(self.playback.lock().unwrap().song);
+        if track_id.as_str() == current {
+            self.seek_to(position);
+        }
+    }
+
+    fn open_uri(&self, uri: &str) -> fdo::Result<()> {
+        Err(fdo::Error::NotSupported(format!(
+            "Unable to open {uri}, songs can only be added to the playlist with edit"
+        )))
+    }
+
+    #[zbus(property)]
+    fn playback_status(&self) -> &'static str {
+        if self.playback.lock().unwrap().song.is_none() {
+            "Stopped"
+        } else if self.sink.is_paused() {
+            "Paused"
+        } else {
+            "Playing"
+        }
+    }
+
+    #[zbus(property)]
+    fn metadata(&self) -> HashMap<&'static str, OwnedValue> {
+        let playback = self.playback.lock().unwrap();
+        let mut metadata = HashMap::new();
+        let id = ObjectPath::try_from(track_path(playback.song)).unwrap();
+        metadata.insert("mpris:trackid", Value::from(id).try_into().unwrap());
+        let Some(song) = playback.song.and_then(|i| playback.playlist.song(i)) else {
+            return metadata;
+        };
+        let title = song.title.clone().unwrap_or_else(|| song.to_string());
+        metadata.insert("xesam:title", Value::from(title).try_into().unwrap());
+        if let Some(artist) = &song.artist {
+            let artists = vec![artist.clone()];
+            metadata.insert("xesam:artist", Value::from(artists).try_into().unwrap());
+        }
+        if let Some(album) = &song.album {
+            metadata.insert(
+                "xesam:album",
+                Value::from(album.clone()).try_into().unwrap(),
+            );
+        }
+        if let Some(length) = song
+            .duration
+            .and_then(|d| i64::try_from(d.as_micros()).ok())
+        {
+            metadata.insert("mpris:length", Value::from(length).try_into().unwrap());
+        }
+        if let Ok(path) = song.path.canonicalize() {
+            let url = format!("file://{}", path.display());
+            metadata.insert("xesam:url", Value::from(url).try_into().unwrap());
+        }
+        metadata
+    }
+
+    ///Volume of the playlist
+    #[zbus(property)]
+    fn volume(&self) -> f64 {
+        f64::from(self.playback.lock().unwrap().playlist.config.volume)
+    }
+
+    #[zbus(property)]
+    fn set_volume(&mut self, volume: f64) -> fdo::Result<()> {
+        #[allow(clippy::cast_possible_truncation)]
+        let volume = volume.max(0.0) as f32;
+        controls::set_volume(&*self.sink, &mut self.playback.lock().unwrap(), volume)
+            .map_err(|e| fdo::Error::InvalidArgs(e.to_string()))
+    }
+
+    ///Microseconds into the current song
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn position(&self) -> i64 {
+        i64::try_from(self.sink.get_pos().as_micros()).unwrap_or(i64::MAX)
+    }
+
+    #[zbus(property)]
+    fn rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn minimum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn maximum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn can_go_next(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_go_previous(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_play(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_pause(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_seek(&self) -> bool {
+        true
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn can_control(&self) -> bool {
+        true
+    }
+}
+
+///Object path identifying the song at `index` of the playlist
+fn track_path(index: Option<usize>) -> String {
+    index.map_or_else(
+        || String::from(NO_TRACK),
+        |i| format!("/org/rplaylist/track/{i}"),
+    )
+}
@@ -0,0 +1,891 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use rodio::queue::SourcesQueueOutput;
+use rodio::{Sink, Source};
+
+use crate::audio::AudioSink;
+use crate::config::RandomMode;
+use crate::controls::{self, ControlMessage, Input, Playback};
+use crate::playlist::{Playlist, PlaylistConfig, Song};
+use crate::{audio, LibError};
+
+///Plays a playlist without taking over the terminal.
+///Can be controlled from other threads while playing.
+pub struct Player {
+    sink: Arc<dyn AudioSink>,
+    ///Output of the sink, until it is connected to an audio device
+    queue: Mutex<Option<SourcesQueueOutput<f32>>>,
+    pub(crate) state: Arc<Mutex<Playback>>,
+}
+
+impl Player {
+    #[must_use]
+    pub fn from_playlist(playlist: Playlist) -> Player {
+        Player::new(Playback::new(None, playlist))
+    }
+
+    pub(crate) fn new(playback: Playback) -> Player {
+        let (sink, queue) = Sink::new_idle();
+        Player {
+            sink: Arc::new(sink),
+            queue: Mutex::new(Some(queue)),
+            state: Arc::new(Mutex::new(playback)),
+        }
+    }
+
+    ///Play on the default audio device until the playlist ends or playback is stopped.
+    ///A player can only play once.
+    ///
+    ///# Errors
+    ///If there is no audio device, the player already played or playback was aborted.
+    pub fn play(&self) -> Result<(), LibError> {
+        self.run(None, Input::Off)
+    }
+
+    ///Decode the playlist as fast as possible without an audio device, to check it can be played.
+    ///A player can only play once.
+    ///
+    ///# Errors
+    ///If a song could not be decoded, the player already played or playback was aborted.
+    ///
+    ///# Panics
+    ///If a thread panicked while playing.
+    pub fn play_silently(&self) -> Result<(), LibError> {
+        let mut queue = self.take_queue()?;
+        let done = Arc::new(AtomicBool::new(false));
+        let done2 = Arc::clone(&done);
+        let drain = thread::spawn(move || {
+            // The queue never ends, it outputs silence while waiting for the next song
+            while !done2.load(Ordering::Relaxed) {
+                queue.by_ref().take(4096).for_each(drop);
+            }
+        });
+        let result = self.play_through(Input::Off);
+        done.store(true, Ordering::Relaxed);
+        let _ = drain.join();
+        result?;
+
+        match self.state.lock().unwrap().failed_songs {
+            0 => Ok(()),
+            n => Err(LibError::DecodeFailed(format!(
+                "{n} songs could not be played"
+            ))),
+        }
+    }
+
+    ///`input` is where playback is controlled from while playing.
+    ///
+    ///# Panics
+    ///If playback panicked.
+    pub(crate) fn run(&self, device: Option<&str>, input: Input) -> Result<(), LibError> {
+        let queue = self.take_queue()?;
+        let release_after = self.state.lock().unwrap().release_after;
+        // The stream can't be sent to other threads, so it stays here while playing in another one
+        let mut device = audio::Device::open(device, queue, release_after)?;
+        #[cfg(all(feature = "mpris", target_os = "linux"))]
+        let mut media = crate::mpris::Media::connect(&self.sink, &self.state)
+            .map_err(|e| eprintln!("Unable to register media controls: {e}"))
+            .ok();
+        let tick = Duration::from_millis(100);
+        thread::scope(|s| {
+            let playing = s.spawn(|| self.play_through(input));
+            let mut error = None;
+            while !playing.is_finished() {
+                thread::sleep(tick);
+                #[cfg(all(feature = "mpris", target_os = "linux"))]
+                if let Some(m) = &mut media {
+                    if let Err(e) = m.update() {
+                        eprintln!("Unable to update media controls: {e}");
+                        media = None;
+                    }
+                }
+                if let Err(e) = device.update(&*self.sink, tick) {
+                    if error.is_none() {
+                        controls::stop_playback(&*self.sink, &self.state);
+                        error = Some(e);
+                    }
+                }
+            }
+            let result = playing
+                .join()
+                .map_err(|_| LibError::Playback("Playback crashed"))?;
+            error.map_or(result, Err)
+        })
+    }
+
+    fn take_queue(&self) -> Result<SourcesQueueOutput<f32>, LibError> {
+        self.queue
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or(LibError::Playback("Player already played"))
+    }
+
+    ///Play the playlist into the sink, which has to be connected to an output already
+    fn play_through(&self, input: Input) -> Result<(), LibError> {
+        #[cfg(unix)]
+        let socket = self.state.lock().unwrap().socket.clone();
+        #[cfg(unix)]
+        if let Some(path) = &socket {
+            crate::socket::listen(path, &self.sink, &self.state)?;
+        }
+        let (handle, tx) = if input == Input::Keys {
+            controls::start(&self.sink, &self.state)
+        } else {
+            controls::start_headless(&self.state)
+        };
+        if input == Input::Commands {
+            controls::start_commands(&self.sink, &self.state);
+        }
+
+        let done = Arc::new(AtomicBool::new(false));
+        let timer = controls::start_sleep_timer(&self.sink, &self.state, &done);
+
+        let repeat = self.state.lock().unwrap().repeat;
+        play_playlist(&tx, &self.state, &*self.sink, repeat);
+
+        done.store(true, Ordering::Relaxed);
+        let _ = timer.join();
+
+        // Tell the controls we are done and wait for it to clean up.
+        let _ = tx.send(ControlMessage::StreamDone);
+        handle
+            .join()
+            .map_err(|_| LibError::Playback("Controls crashed"))?;
+        #[cfg(unix)]
+        if let Some(path) = socket {
+            let _ = std::fs::remove_file(path);
+        }
+
+        if self.state.lock().unwrap().control_error {
+            return Err(LibError::Playback("Playback aborted"));
+        }
+        Ok(())
+    }
+
+    ///Continue with the next song
+    pub fn skip(&self) {
+        controls::next_song(&*self.sink, &self.state);
+    }
+
+    pub fn pause(&self) {
+        self.sink.pause();
+    }
+
+    pub fn unpause(&self) {
+        self.sink.play();
+    }
+
+    ///Stop playing, `play` returns once the current song is cleared
+    pub fn stop(&self) {
+        controls::stop_playback(&*self.sink, &self.state);
+    }
+
+    ///Play the song at `index` next, after songs queued before.
+    ///Queued songs are removed from the queue once they start playing.
+    ///
+    ///# Errors
+    ///If there is no song at `index`.
+    ///
+    ///# Panics
+    ///If a thread panicked while playing.
+    pub fn enqueue(&self, index: usize) -> Result<(), LibError> {
+        controls::queue_song(&mut self.state.lock().unwrap(), index)
+            .map_err(LibError::InvalidArgument)
+    }
+
+    ///Volume of the whole playlist, the volume of each song is applied on top
+    ///
+    ///# Errors
+    ///If the volume is negative or too loud.
+    ///
+    ///# Panics
+    ///If a thread panicked while playing.
+    pub fn set_volume(&self, volume: f32) -> Result<(), LibError> {
+        controls::set_volume(&*self.sink, &mut self.state.lock().unwrap(), volume)
+    }
+
+    ///The playlist with all changes made while playing
+    ///
+    ///# Panics
+    ///If a thread panicked while playing.
+    #[must_use]
+    pub fn playlist(&self) -> Playlist {
+        self.state.lock().unwrap().playlist.clone()
+    }
+}
+
+fn play_playlist(
+    tx: &Sender<ControlMessage>, state: &Mutex<Playback>, sink: &dyn AudioSink, repeat: bool,
+) {
+    if repeat {
+        while !state.lock().unwrap().stopped() {
+            let random = state.lock().unwrap().playlist.config.random.clone();
+            if matches!(random, RandomMode::True | RandomMode::Weighted) {
+                play_true_random(tx, state, sink);
+            } else {
+                play_normal(tx, state, sink);
+            }
+        }
+    } else {
+        play_normal(tx, state, sink);
+    }
+}
+
+fn play_normal(tx: &Sender<ControlMessage>, state: &Mutex<Playback>, sink: &dyn AudioSink) {
+    let mut order = {
+        let state = state.lock().unwrap();
+        let count = state.playlist.song_count();
+        let mut order = match state.playlist.config.random {
+            RandomMode::Off => (0..count).collect(),
+            _ => shuffled_order(count, state.song),
+        };
+        order.retain(|i| !state.skipped.contains(i));
+        order
+    };
+
+    let start = resume(state, &mut order);
+    limit_order(&mut state.lock().unwrap(), &mut order);
+    play_order(tx, state, sink, order, start);
+}
+
+fn play_true_random(tx: &Sender<ControlMessage>, state: &Mutex<Playback>, sink: &dyn AudioSink) {
+    let mut order = {
+        let state = state.lock().unwrap();
+        let mut order = random_order(&state.playlist, state.song);
+        order.retain(|i| !state.skipped.contains(i));
+        order
+    };
+    let start = resume(state, &mut order);
+    limit_order(&mut state.lock().unwrap(), &mut order);
+    play_order(tx, state, sink, order, start);
+}
+
+///Cut the first order after `Playback::limit` songs. The songs that were cut are skipped
+///from then on, so repeating plays the same ones again.
+fn limit_order(state: &mut Playback, order: &mut Vec<usize>) {
+    let Some(limit) = state.limit.take() else {
+        return;
+    };
+    order.truncate(limit);
+    let kept: HashSet<usize> = order.iter().copied().collect();
+    let count = state.playlist.song_count();
+    state
+        .skipped
+        .extend((0..count).filter(|i| !kept.contains(i)));
+}
+
+///Every song once in random order.
+///When repeating, `last` is the song that ended the previous order, it won't start this one.
+///With a single song that song starts every order anyway.
+fn shuffled_order(count: usize, last: Option<usize>) -> Vec<usize> {
+    let mut rng = rand::thread_rng();
+    let mut order: Vec<usize> = (0..count).collect();
+    order.shuffle(&mut rng);
+    if count > 1 && order.first() == last.as_ref() {
+        order.swap(0, rng.gen_range(1..count));
+    }
+    order
+}
+
+///Every song of the order is picked at random, except that no song is picked twice in a row.
+///`last` is the song played before the order, it isn't picked first either.
+///In weighted mode, songs that were played less often are more likely to be picked.
+fn random_order(playlist: &Playlist, mut last: Option<usize>) -> Vec<usize> {
+    let count = playlist.song_count();
+    if count < 2 {
+        // Nothing else to pick, so the only song has to repeat
+        return vec![0; count];
+    }
+    let mut rng = rand::thread_rng();
+    let mut order = Vec::with_capacity(count);
+    if playlist.config.random == RandomMode::Weighted {
+        let weights: Vec<f64> = (0..count)
+            .map(|i| 1.0 / (f64::from(playlist.song(i).unwrap().play_count) + 1.0))
+            .collect();
+        let mut weighted = WeightedIndex::new(&weights).unwrap();
+        for _ in 0..count {
+            if let Some(l) = last {
+                // There are other songs with a positive weight, so this can't fail
+                weighted.update_weights(&[(l, &0.0)]).unwrap();
+            }
+            let i = weighted.sample(&mut rng);
+            if let Some(l) = last {
+                weighted.update_weights(&[(l, &weights[l])]).unwrap();
+            }
+            order.push(i);
+            last = Some(i);
+        }
+    } else {
+        for _ in 0..count {
+            let i = match last {
+                // Skip over the last song
+                Some(l) => (l + rng.gen_range(1..count)) % count,
+                None => rng.gen_range(0..count),
+            };
+            order.push(i);
+            last = Some(i);
+        }
+    }
+    order
+}
+
+///Start the order with the song playback was quit at last time.
+///Returns the position in the song to continue at.
+fn resume(state: &Mutex<Playback>, order: &mut Vec<usize>) -> Option<Duration> {
+    let (point, random) = {
+        let mut state = state.lock().unwrap();
+        (state.resume.take()?, state.playlist.config.random.clone())
+    };
+    resume_order(order, point.index, &random);
+    Some(point.position)
+}
+
+///In order songs continue from `index`, random orders just start with it.
+fn resume_order(order: &mut Vec<usize>, index: usize, random: &RandomMode) {
+    match order.iter().position(|&i| i == index) {
+        Some(p) if *random == RandomMode::Off => {
+            order.drain(..p);
+        }
+        Some(p) => order.swap(0, p),
+        None => order.insert(0, index),
+    }
+}
+
+///Position of the song at `index` in the order.
+///If the order doesn't contain it, it is added right after `position`.
+fn goto_position(order: &mut Vec<usize>, position: usize, index: usize) -> usize {
+    order.iter().position(|&i| i == index).unwrap_or_else(|| {
+        order.insert(position + 1, index);
+        position + 1
+    })
+}
+
+///Song appended to the sink ahead of time, so it starts right when the current one ends
+struct Upcoming {
+    index: usize,
+    song: Result<audio::Queued, String>,
+}
+
+///Copies of what playing a song needs, so the state doesn't stay locked while it plays
+struct Started {
+    song: Song,
+    ///Index and song that plays after it
+    next: Option<(usize, Song)>,
+    config: PlaylistConfig,
+}
+
+///Make the song at `position` of the order the current one and configure the sink for it.
+///None once playback is stopped.
+fn start_song(
+    state: &Mutex<Playback>, sink: &dyn AudioSink, order: &[usize], position: usize,
+    boost: &audio::Boost,
+) -> Option<Started> {
+    let mut state = state.lock().unwrap();
+    if state.stopped() {
+        return None;
+    }
+    let index = order[position];
+    state.position = position;
+    state.song = Some(index);
+    state.next = if state.repeat_one {
+        Some(index)
+    } else {
+        order.get(position + 1).copied()
+    };
+    let next = state.queue.front().copied().or(state.next);
+    let playlist = &state.playlist;
+    let song = playlist.song(index).unwrap();
+    // Still locked, so the controls can't change the song before its volume is applied
+    audio::config_sink(
+        sink,
+        &song.config,
+        &playlist.config,
+        state.muted,
+        state.max_volume,
+        boost,
+    );
+    let preview = state.preview;
+    Some(Started {
+        song: previewed(song.clone(), preview),
+        next: next.map(|i| (i, previewed(playlist.song(i).unwrap().clone(), preview))),
+        config: playlist.config.clone(),
+    })
+}
+
+///The song as it plays in a preview, which ends it `preview` after its start.
+///The copy is only played, so the playlist keeps the real end.
+pub(crate) fn previewed(mut song: Song, preview: Option<Duration>) -> Song {
+    if let Some(preview) = preview {
+        let end = song.config.start.unwrap_or_default() + preview;
+        song.config.end = Some(song.config.end.map_or(end, |e| e.min(end)));
+    }
+    song
+}
+
+///Play songs by their index in the given order, following jumps requested by the controls.
+///The next song is queued while the current one plays, so songs of the same order play without gaps.
+///Crossfading also only happens between songs of the same order.
+///`start` is the position to start the first song at.
+fn play_order(
+    tx: &Sender<ControlMessage>, state: &Mutex<Playback>, sink: &dyn AudioSink,
+    mut order: Vec<usize>, mut start: Option<Duration>,
+) {
+    let mut position = 0;
+    let mut upcoming: Option<Upcoming> = None;
+    let boost = state.lock().unwrap().boost.clone();
+    while position < order.len() {
+        let index = order[position];
+        let Some(Started { song, next, config }) =
+            start_song(state, sink, &order, position, &boost)
+        else {
+            break;
+        };
+        notify(tx, state, sink, ControlMessage::StartSong(index));
+
+        let current = match upcoming.take() {
+            Some(u) if u.index == index => u.song,
+            u => {
+                discard(sink, u);
+                open_song(&song, &config).map(|s| {
+                    let limiter = config.limiter.then_some(&boost);
+                    audio::queue(
+                        sink,
+                        s,
+                        None,
+                        config.fade,
+                        config.crossfade.is_some(),
+                        limiter,
+                    )
+                })
+            }
+        };
+        match current {
+            Ok(mut current) => {
+                if let Some(start) = start.take() {
+                    // If this fails we just play the song from the beginning
+                    let _ = sink.try_seek(start);
+                }
+                if current.duration.is_none() {
+                    current.duration = song
+                        .duration
+                        .map(|d| audio::played_duration(d, &song.config, config.tempo));
+                }
+                notify(
+                    tx,
+                    state,
+                    sink,
+                    ControlMessage::SongDuration(current.duration),
+                );
+                let gap = config.gap.filter(|_| config.crossfade.is_none());
+                // With a gap, the next song can't follow right away
+                upcoming = next
+                    .as_ref()
+                    .filter(|_| gap.is_none())
+                    .map(|(i, next_song)| Upcoming {
+                        index: *i,
+                        song: queue_next(sink, &current, &song, next_song, &config, &boost),
+                    });
+                current.wait();
+                count_play(state, index);
+                if let Some(gap) = gap.filter(|_| next.is_some()) {
+                    wait_gap(state, sink, gap);
+                }
+            }
+            Err(msg) => {
+                state.lock().unwrap().failed_songs += 1;
+                notify(tx, state, sink, ControlMessage::StreamError(msg));
+            }
+        }
+
+        let mut state = state.lock().unwrap();
+        if let Some(index) = state.goto.take() {
+            state.jump = Some(goto_position(&mut order, position, index));
+            start = state.seek.take();
+        }
+        position = match state.jump.take() {
+            Some(p) => {
+                discard(sink, upcoming.take());
+                p
+            }
+            None if state.repeat_one => position,
+            None => {
+                // Queued songs are played in between, the rest of the order stays the same
+                if let Some(queued) = state.queue.pop_front() {
+                    order.insert(position + 1, queued);
+                }
+                position + 1
+            }
+        };
+    }
+    discard(sink, upcoming);
+}
+
+///Count the song at `index` as played if it ended by itself.
+///Songs that were skipped, stopped or moved away from by the controls don't count.
+fn count_play(state: &Mutex<Playback>, index: usize) {
+    let mut state = state.lock().unwrap();
+    if state.stopped() || state.jump.is_some() || state.goto.is_some() {
+        return;
+    }
+    let song = state.playlist.song_mut(index).unwrap();
+    song.play_count += 1;
+    song.last_played = Some(SystemTime::now());
+}
+
+///Tell the controls about playback. Once they are gone, playback can't be controlled anymore,
+///so it stops.
+fn notify(
+    tx: &Sender<ControlMessage>, state: &Mutex<Playback>, sink: &dyn AudioSink,
+    message: ControlMessage,
+) {
+    if tx.send(message).is_err() {
+        controls::stop_playback(sink, state);
+    }
+}
+
+///Silence between songs. Doesn't count while paused, ends early once playback is stopped
+///or another song is requested.
+fn wait_gap(state: &Mutex<Playback>, sink: &dyn AudioSink, gap: Duration) {
+    let step = Duration::from_millis(50);
+    let mut left = gap;
+    while !left.is_zero() {
+        {
+            let state = state.lock().unwrap();
+            if state.stopped() || state.jump.is_some() || state.goto.is_some() {
+                return;
+            }
+        }
+        thread::sleep(step.min(left));
+        if !sink.is_paused() {
+            left = left.saturating_sub(step);
+        }
+    }
+}
+
+///Queue `next` to play right after `current`, crossfading into it if configured.
+fn queue_next(
+    sink: &dyn AudioSink, current: &audio::Queued, song: &Song, next: &Song,
+    config: &PlaylistConfig, boost: &audio::Boost,
+) -> Result<audio::Queued, String> {
+    let source = open_song(next, config)?;
+    let tail = config.crossfade.and_then(|crossfade| {
+        let total = current.duration?;
+        // We can't crossfade a part of the song that was already played
+        let start = total
+            .saturating_sub(crossfade)
+            .max(current.position() + Duration::from_millis(100));
+        let duration = total.checked_sub(start).filter(|d| !d.is_zero())?;
+        let mut tail = open_song(song, config).ok()?;
+        tail.try_seek(start).ok()?;
+        current.cut(start);
+        let gain = if next.config.volume > 0.0 {
+            song.config.volume / next.config.volume
+        } else {
+            0.0
+        };
+        Some(audio::Tail {
+            source: tail,
+            start,
+            duration,
+            gain,
+        })
+    });
+    Ok(audio::queue(
+        sink,
+        source,
+        tail,
+        config.fade,
+        config.crossfade.is_some(),
+        config.limiter.then_some(boost),
+    ))
+}
+
+///Remove a song queued ahead of time from the sink
+fn discard(sink: &dyn AudioSink, upcoming: Option<Upcoming>) {
+    if let Some(Upcoming {
+        song: Ok(queued), ..
+    }) = upcoming
+    {
+        sink.clear();
+        sink.play();
+        // Make sure it's gone before anything else is queued
+        queued.wait();
+    }
+}
+
+pub(crate) fn open_song(song: &Song, config: &PlaylistConfig) -> Result<audio::SongSource, String> {
+    let source = audio::open(&song.path).map_err(|e| e.to_string())?;
+    Ok(audio::prepare(source, &song.config, config.tempo))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::playlist::SongConfig;
+
+    #[test]
+    fn resume_order_in_order() {
+        let mut order = vec![0, 1, 2, 3];
+        resume_order(&mut order, 2, &RandomMode::Off);
+        assert_eq!(order, vec![2, 3]);
+    }
+
+    #[test]
+    fn resume_order_shuffled() {
+        let mut order = vec![3, 1, 0, 2];
+        resume_order(&mut order, 0, &RandomMode::Shuffle);
+        assert_eq!(order[0], 0);
+        assert_eq!(order.len(), 4);
+    }
+
+    #[test]
+    fn limit_first_order() {
+        let mut p = Playlist::new();
+        for path in ["a.mp3", "b.mp3", "c.mp3", "d.mp3"] {
+            p.add_song(Song::new(PathBuf::from(path))).unwrap();
+        }
+        let mut state = Playback::new(None, p);
+        state.limit = Some(2);
+        let mut order = vec![3, 1, 0, 2];
+        limit_order(&mut state, &mut order);
+        assert_eq!(order, vec![3, 1]);
+        assert_eq!(state.skipped, HashSet::from([0, 2]));
+
+        // Later orders are left alone, the skipped songs keep them to the same songs
+        let mut order = vec![1, 3];
+        limit_order(&mut state, &mut order);
+        assert_eq!(order, vec![1, 3]);
+
+        state.limit = Some(10);
+        let mut order = vec![1, 3];
+        limit_order(&mut state, &mut order);
+        assert_eq!(order, vec![1, 3]);
+    }
+
+    #[test]
+    fn preview_ends_songs_early() {
+        let mut song = Song::new(PathBuf::from("a.mp3"));
+        song.config.start = Some(Duration::from_secs(10));
+        let preview = Some(Duration::from_secs(20));
+        assert_eq!(
+            previewed(song.clone(), preview).config.end,
+            Some(Duration::from_secs(30))
+        );
+        song.config.end = Some(Duration::from_secs(15));
+        assert_eq!(
+            previewed(song.clone(), preview).config.end,
+            Some(Duration::from_secs(15))
+        );
+        assert_eq!(previewed(song.clone(), None), song);
+    }
+
+    #[test]
+    fn goto_position_in_order() {
+        let mut order = vec![0, 1, 2, 3];
+        assert_eq!(goto_position(&mut order, 0, 2), 2);
+        assert_eq!(order, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn goto_position_missing() {
+        let mut order = vec![3, 3, 1];
+        assert_eq!(goto_position(&mut order, 1, 0), 2);
+        assert_eq!(order, vec![3, 3, 0, 1]);
+    }
+
+    #[test]
+    fn valid_set_volume() {
+        let player = Player::from_playlist(Playlist::new());
+        player.set_volume(2.0).expect("2 is a valid volume");
+        assert!((player.playlist().config.volume - 2.0).abs() < f32::EPSILON);
+        assert!(player.set_volume(-1.0).is_err());
+    }
+
+    #[test]
+    fn max_volume_limits_sink() {
+        let (sink, _queue) = Sink::new_idle();
+        let song = SongConfig {
+            volume: 3.0,
+            ..Default::default()
+        };
+        let config = PlaylistConfig {
+            volume: 2.0,
+            ..Default::default()
+        };
+
+        let boost = audio::Boost::new();
+        assert!(audio::config_sink(
+            &sink, &song, &config, false, 4.0, &boost
+        ));
+        assert!((sink.volume() - 4.0).abs() < f32::EPSILON);
+        assert!(!audio::config_sink(
+            &sink, &song, &config, false, 6.0, &boost
+        ));
+        assert!((sink.volume() - 6.0).abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn limiter_takes_boost() {
+        let (sink, _queue) = Sink::new_idle();
+        let song = SongConfig::default();
+        let config = PlaylistConfig {
+            volume: 2.5,
+            limiter: true,
+            ..Default::default()
+        };
+
+        let boost = audio::Boost::new();
+        assert!(audio::config_sink(
+            &sink, &song, &config, false, 2.0, &boost
+        ));
+        assert!((sink.volume() - 1.0).abs() < f32::EPSILON);
+        assert!((boost.get() - 2.0).abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn gap_ends_on_jump() {
+        let (sink, _queue) = Sink::new_idle();
+        let state = Mutex::new(Playback::new(None, Playlist::new()));
+        state.lock().unwrap().jump = Some(1);
+        let start = std::time::Instant::now();
+        wait_gap(&state, &sink, Duration::from_secs(30));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    #[cfg(all(feature = "mp3", feature = "wav"))]
+    fn play_order_into_fake_sink() {
+        let mut p = Playlist::new();
+        p.add_song(Song::new(PathBuf::from("test_data/test.mp3")))
+            .unwrap();
+        p.add_song(Song::new(PathBuf::from("test_data/empty.playlist")))
+            .unwrap();
+        p.add_song(Song::new(PathBuf::from("test_data/nested/silence.wav")))
+            .unwrap();
+        let state = Mutex::new(Playback::new(None, p));
+        let sink = audio::FakeSink::default();
+        let (tx, rx) = std::sync::mpsc::channel();
+        play_order(&tx, &state, &sink, vec![2, 1, 0], None);
+        drop(tx);
+
+        let started: Vec<usize> = rx
+            .iter()
+            .filter_map(|m| match m {
+                ControlMessage::StartSong(i) => Some(i),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(started, vec![2, 1, 0]);
+        assert_eq!(sink.count("append"), 2);
+        let state = state.lock().unwrap();
+        assert_eq!(state.failed_songs, 1);
+        assert_eq!(state.playlist.song(0).unwrap().play_count, 1);
+        assert!(state.playlist.song(0).unwrap().last_played.is_some());
+        assert_eq!(state.playlist.song(1).unwrap().play_count, 0);
+    }
+
+    #[test]
+    #[cfg(all(feature = "mp3", feature = "wav"))]
+    fn skipped_song_not_counted() {
+        let mut p = Playlist::new();
+        p.add_song(Song::new(PathBuf::from("test_data/test.mp3")))
+            .unwrap();
+        p.add_song(Song::new(PathBuf::from("test_data/nested/silence.wav")))
+            .unwrap();
+        let state = Mutex::new(Playback::new(None, p));
+        // As if next was pressed while the first song played
+        state.lock().unwrap().jump = Some(1);
+        let sink = audio::FakeSink::default();
+        let (tx, _rx) = std::sync::mpsc::channel();
+        play_order(&tx, &state, &sink, vec![0, 1], None);
+
+        let state = state.lock().unwrap();
+        let skipped = state.playlist.song(0).unwrap();
+        assert_eq!(skipped.play_count, 0);
+        assert_eq!(skipped.last_played, None);
+        assert_eq!(state.playlist.song(1).unwrap().play_count, 1);
+    }
+
+    #[test]
+    fn play_order_stops_without_controls() {
+        let mut p = Playlist::new();
+        p.add_song(Song::new(PathBuf::from("test_data/test.mp3")))
+            .unwrap();
+        p.add_song(Song::new(PathBuf::from("test_data/nested/silence.wav")))
+            .unwrap();
+        let state = Mutex::new(Playback::new(None, p));
+        let sink = audio::FakeSink::default();
+        let (tx, rx) = std::sync::mpsc::channel();
+        drop(rx);
+        play_order(&tx, &state, &sink, vec![0, 1], None);
+        let state = state.lock().unwrap();
+        assert!(state.stopped());
+        assert_eq!(state.playlist.song(1).unwrap().play_count, 0);
+    }
+
+    #[test]
+    fn valid_enqueue() {
+        let mut p = Playlist::new();
+        p.add_song(Song::new(PathBuf::from("a.mp3"))).unwrap();
+        let player = Player::from_playlist(p);
+        player.enqueue(0).expect("Song 0 exists");
+        assert!(player.enqueue(1).is_err());
+        assert_eq!(player.state.lock().unwrap().queue, VecDeque::from([0]));
+    }
+
+    #[test]
+    fn weighted_random_order() {
+        let mut p = Playlist::new();
+        p.config.random = RandomMode::Weighted;
+        let mut played = Song::new(PathBuf::from("a.mp3"));
+        played.play_count = 1_000_000;
+        p.add_song(played).unwrap();
+        p.add_song(Song::new(PathBuf::from("b.mp3"))).unwrap();
+        p.add_song(Song::new(PathBuf::from("c.mp3"))).unwrap();
+
+        let order: Vec<usize> = (0..50).flat_map(|_| random_order(&p, None)).collect();
+        assert_eq!(order.len(), 150);
+        assert!(order.iter().filter(|&&i| i == 0).count() < 10);
+    }
+
+    #[test]
+    fn random_order_no_repeats() {
+        let mut p = Playlist::new();
+        p.add_song(Song::new(PathBuf::from("a.mp3"))).unwrap();
+        p.add_song(Song::new(PathBuf::from("b.mp3"))).unwrap();
+        for mode in [RandomMode::True, RandomMode::Weighted] {
+            p.config.random = mode;
+            let order = random_order(&p, Some(0));
+            assert_eq!(order, vec![1, 0]);
+        }
+    }
+
+    #[test]
+    fn random_order_single_song() {
+        let mut p = Playlist::new();
+        p.add_song(Song::new(PathBuf::from("a.mp3"))).unwrap();
+        assert_eq!(random_order(&p, Some(0)), vec![0]);
+    }
+
+    #[test]
+    fn shuffled_order_new_start() {
+        for _ in 0..20 {
+            let order = shuffled_order(3, Some(1));
+            assert_ne!(order[0], 1);
+            assert_eq!(order.len(), 3);
+        }
+        assert_eq!(shuffled_order(1, Some(0)), vec![0]);
+    }
+}
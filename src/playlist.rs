@@ -1,81 +1,473 @@
-use std::fmt;
+use std::cmp::Ordering;
+use std::collections::HashSet;
 use std::fmt::Formatter;
-use std::path::PathBuf;
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::str::Chars;
+use std::time::{Duration, SystemTime};
+use std::{fmt, fs};
 
+use rand::seq::SliceRandom;
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 
-use crate::config::RandomMode;
+use crate::config::{RandomMode, SortKey};
+use crate::metadata::Tags;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 #[derive(Serialize, Deserialize)]
 pub struct Playlist {
+    #[serde(default)]
     pub config: PlaylistConfig,
+    #[serde(default)]
     songs: Vec<Song>,
+    ///Fields this version doesn't know, like those of newer versions, kept so saving doesn't drop them
+    #[serde(flatten)]
+    pub(crate) unknown: Map<String, Value>,
 }
 
 impl Playlist {
+    #[must_use]
     pub fn new() -> Playlist {
         Playlist {
             config: PlaylistConfig::new(),
             songs: vec![],
+            unknown: Map::new(),
         }
     }
+    #[must_use]
     pub fn song(&self, index: usize) -> Option<&Song> {
         self.songs.get(index)
     }
     pub fn song_mut(&mut self, index: usize) -> Option<&mut Song> {
         self.songs.get_mut(index)
     }
+    #[must_use]
     pub fn song_count(&self) -> usize {
         self.songs.len()
     }
+    ///# Errors
+    ///If the song is already in the playlist, even under a different path.
     pub fn add_song(&mut self, song: Song) -> Result<(), String> {
-        for s in self.songs.as_slice() {
-            if s.path == song.path {
-                return Err(format!("Song already exists: {}", s.path.display()));
+        let mut known = self.known_paths();
+        self.insert_song(song, &mut known)
+    }
+    ///Like `add_song`, but duplicates are skipped quietly.
+    ///Returns how many songs were added and how many were skipped.
+    pub fn add_songs(&mut self, songs: Vec<Song>) -> (usize, usize) {
+        let count = songs.len();
+        let mut known = self.known_paths();
+        let mut added = 0;
+        for song in songs {
+            if self.insert_song(song, &mut known).is_ok() {
+                added += 1;
             }
         }
+        (added, count - added)
+    }
+    fn insert_song(&mut self, song: Song, known: &mut HashSet<PathBuf>) -> Result<(), String> {
+        if !known.insert(canonical(&song.path)) {
+            return Err(format!("Song already exists: {}", song.path.display()));
+        }
         self.songs.push(song);
         Ok(())
     }
+    fn known_paths(&self) -> HashSet<PathBuf> {
+        self.songs.iter().map(|s| canonical(&s.path)).collect()
+    }
+    ///# Errors
+    ///If there is no song at `index`.
+    pub fn remove_song(&mut self, index: usize) -> Result<Song, String> {
+        if index < self.songs.len() {
+            Ok(self.songs.remove(index))
+        } else {
+            Err(format!("No song at index {index}"))
+        }
+    }
+    #[must_use]
+    pub fn into_songs(self) -> Vec<Song> {
+        self.songs
+    }
+    ///Remove all songs, the config stays
+    pub fn clear(&mut self) {
+        self.songs.clear();
+    }
+    ///`to` is the index of the song after moving it
+    ///
+    ///# Errors
+    ///If there is no song at `from` or `to`.
+    pub fn move_song(&mut self, from: usize, to: usize) -> Result<(), String> {
+        let len = self.songs.len();
+        if from >= len || to >= len {
+            return Err(format!(
+                "Unable to move song from {from} to {to}, playlist has {len} songs"
+            ));
+        }
+        let song = self.songs.remove(from);
+        self.songs.insert(to, song);
+        Ok(())
+    }
+    ///Songs that compare equal keep their order
+    pub fn sort_by(&mut self, key: SortKey) {
+        match key {
+            SortKey::Name => self.songs.sort_by_cached_key(ToString::to_string),
+            SortKey::Path => self.songs.sort_by(|a, b| a.path.cmp(&b.path)),
+            SortKey::Natural => self.sort_natural(),
+            SortKey::Plays => self.songs.sort_by(most_played),
+        }
+    }
+    ///By path, numbers by their value, see `natural_path_cmp`
+    pub fn sort_natural(&mut self) {
+        self.songs
+            .sort_by(|a, b| natural_path_cmp(&a.path, &b.path));
+    }
+    ///Unlike `RandomMode`, this changes the stored order
+    pub fn shuffle(&mut self) {
+        self.songs.shuffle(&mut rand::thread_rng());
+    }
     pub fn validate_songs<F>(&mut self, f: F)
     where
-        F: FnMut(&Song) -> bool,
+        F: FnMut(&mut Song) -> bool,
     {
-        self.songs.retain(f);
+        self.songs.retain_mut(f);
     }
 }
 
-impl fmt::Display for Playlist {
-    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+impl Playlist {
+    ///Indices of songs whose name, file name, title or artist contain `query`, ignoring case
+    #[must_use]
+    pub fn matching(&self, query: &str) -> Vec<usize> {
+        let query = query.to_lowercase();
+        let contains = |text: &str| text.to_lowercase().contains(&query);
+        (0..self.songs.len())
+            .filter(|&i| {
+                let s = &self.songs[i];
+                contains(&s.to_string())
+                    || s.path
+                        .file_name()
+                        .is_some_and(|n| contains(&n.to_string_lossy()))
+                    || s.title.as_deref().is_some_and(contains)
+                    || s.artist.as_deref().is_some_and(contains)
+            })
+            .collect()
+    }
+
+    ///Sort `indices` of songs by `key`, leaving the songs where they are
+    pub fn sort_indices(&self, indices: &mut [usize], key: SortKey) {
+        let songs = &self.songs;
+        match key {
+            SortKey::Name => indices.sort_by_cached_key(|&i| songs[i].to_string()),
+            SortKey::Path => indices.sort_by(|&a, &b| songs[a].path.cmp(&songs[b].path)),
+            SortKey::Natural => {
+                indices.sort_by(|&a, &b| natural_path_cmp(&songs[a].path, &songs[b].path));
+            }
+            SortKey::Plays => indices.sort_by(|&a, &b| most_played(&songs[a], &songs[b])),
+        }
+    }
+
+    ///Cheap, only durations stored by scanning are counted
+    #[must_use]
+    pub fn stats(&self) -> Stats {
+        Stats {
+            songs: self.songs.len(),
+            duration: self.songs.iter().filter_map(|s| s.duration).sum(),
+            unscanned: self.songs.iter().filter(|s| s.duration.is_none()).count(),
+            random: self.config.random.clone(),
+            volume: self.config.volume,
+        }
+    }
+
+    ///Only the songs at `shown` are listed if given, in that order.
+    ///`durations` are shown in front of the songs they belong to, followed by the total.
+    ///If `filtered`, how many songs are shown follows.
+    fn fmt_with(
+        &self, f: &mut Formatter, shown: Option<&[usize]>, durations: Option<&[Option<Duration>]>,
+        filtered: bool, plays: bool,
+    ) -> fmt::Result {
         write!(f, "  Settings:")?;
         write!(f, "\n{}", self.config)?;
         write!(f, "\n  Songs:")?;
-        for s in &self.songs {
-            write!(f, "\n{s}")?;
+        let all: Vec<usize> = (0..self.songs.len()).collect();
+        let indices = shown.unwrap_or(&all);
+        let width = self.songs.len().saturating_sub(1).to_string().len();
+        for &i in indices {
+            write!(f, "\n[{i:>width$}] ")?;
+            if let Some(durations) = durations {
+                match durations.get(i).copied().flatten() {
+                    Some(d) => write!(f, "{} ", format_duration(d))?,
+                    None => write!(f, "??:?? ")?,
+                }
+            }
+            write!(f, "{}", self.songs[i])?;
+            if plays {
+                write!(f, " ({})", Plays(&self.songs[i]))?;
+            }
+        }
+        if let Some(durations) = durations {
+            let shown_durations = indices.iter().map(|&i| durations.get(i).copied().flatten());
+            let total: Duration = shown_durations.clone().flatten().sum();
+            write!(f, "\n  Total: {}", format_duration(total))?;
+            let unknown = shown_durations.filter(Option::is_none).count();
+            if unknown > 0 {
+                write!(f, " (without {unknown} unknown)")?;
+            }
+        }
+        if filtered {
+            write!(
+                f,
+                "\n  {} of {} songs match",
+                indices.len(),
+                self.songs.len()
+            )?;
         }
         Ok(())
     }
 }
 
+impl fmt::Display for Playlist {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        self.fmt_with(f, None, None, false, false)
+    }
+}
+
+///Displays only some songs of a playlist, see `Playlist::matching`, or all in another order
+pub struct Filtered<'a> {
+    pub playlist: &'a Playlist,
+    ///Indices of the songs to show, in the order they are shown
+    pub indices: Vec<usize>,
+    ///In the order of the songs, `None` if unknown. Shown with the total if given.
+    pub durations: Option<Vec<Option<Duration>>>,
+    ///Whether songs were left out, to tell how many match
+    pub filtered: bool,
+    ///Show how often and when each song played to its end
+    pub plays: bool,
+}
+
+impl fmt::Display for Filtered<'_> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        self.playlist.fmt_with(
+            f,
+            Some(&self.indices),
+            self.durations.as_deref(),
+            self.filtered,
+            self.plays,
+        )
+    }
+}
+
+///How often and how long ago a song played to its end, like "3 plays, last yesterday"
+struct Plays<'a>(&'a Song);
+
+impl fmt::Display for Plays<'_> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let song = self.0;
+        match song.play_count {
+            0 => return write!(f, "not played"),
+            1 => write!(f, "1 play")?,
+            n => write!(f, "{n} plays")?,
+        }
+        let Some(last) = song.last_played else {
+            return Ok(());
+        };
+        // Clocks can go back, then it just played
+        let days = last.elapsed().unwrap_or_default().as_secs() / (24 * 60 * 60);
+        match days {
+            0 => write!(f, ", last today"),
+            1 => write!(f, ", last yesterday"),
+            n => write!(f, ", last {n} days ago"),
+        }
+    }
+}
+
+///Summary of a playlist, see `Playlist::stats`
+pub struct Stats {
+    pub songs: usize,
+    ///Total of the stored durations
+    pub duration: Duration,
+    ///Songs without a stored duration
+    pub unscanned: usize,
+    pub random: RandomMode,
+    pub volume: f32,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} songs, {}",
+            self.songs,
+            format_duration(self.duration)
+        )?;
+        if self.unscanned > 0 {
+            write!(f, " (without {} not scanned)", self.unscanned)?;
+        }
+        write!(
+            f,
+            "; Random mode: {}; Amplify: {}",
+            self.random, self.volume
+        )
+    }
+}
+
+impl Default for Playlist {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+///Different paths to the same file are the same song.
+///Missing files can't be canonicalized, so their path is used as it is.
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+///Most played first, songs played equally often by when they last played
+fn most_played(a: &Song, b: &Song) -> Ordering {
+    b.play_count
+        .cmp(&a.play_count)
+        .then(b.last_played.cmp(&a.last_played))
+}
+
+///Compares like people do, numbers by their value: "track2" comes before "track10".
+///Letters are compared ignoring case.
+#[must_use]
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut left, mut right) = (a.chars().peekable(), b.chars().peekable());
+    loop {
+        let ord = match (left.peek(), right.peek()) {
+            // Equal apart from case or leading zeros, which still need some order
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(l), Some(r)) if l.is_ascii_digit() && r.is_ascii_digit() => {
+                let (l, r) = (take_number(&mut left), take_number(&mut right));
+                let (l, r) = (l.trim_start_matches('0'), r.trim_start_matches('0'));
+                l.len().cmp(&r.len()).then_with(|| l.cmp(r))
+            }
+            (Some(l), Some(r)) => {
+                let ord = l.to_lowercase().cmp(r.to_lowercase());
+                left.next();
+                right.next();
+                ord
+            }
+        };
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+}
+
+fn take_number(chars: &mut Peekable<Chars>) -> String {
+    let mut number = String::new();
+    while let Some(c) = chars.next_if(char::is_ascii_digit) {
+        number.push(c);
+    }
+    number
+}
+
+///Like `natural_cmp`, directory by directory, so songs of a directory stay together
+#[must_use]
+pub fn natural_path_cmp(a: &Path, b: &Path) -> Ordering {
+    let (mut left, mut right) = (a.iter(), b.iter());
+    loop {
+        let (l, r) = match (left.next(), right.next()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(l), Some(r)) => (l, r),
+        };
+        let ord = natural_cmp(&l.to_string_lossy(), &r.to_string_lossy());
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+}
+
+///mm:ss, or h:mm:ss if needed
+#[must_use]
+pub fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{:02}:{:02}", secs / 60, secs % 60)
+    }
+}
+
+///Most stars a song can be rated
+pub const MAX_RATING: u8 = 5;
+
 #[derive(Debug, PartialEq, Clone)]
 #[derive(Serialize, Deserialize)]
 pub struct Song {
     pub path: PathBuf,
+    #[serde(default)]
     pub config: SongConfig,
+    ///Duration of the whole file, cached when scanning or validating.
+    ///Not updated if the file changes.
+    #[serde(default)]
+    pub duration: Option<Duration>,
+    ///How often the song played to its end. Skipping or stopping it doesn't count.
+    #[serde(default)]
+    pub play_count: u32,
+    ///When the song last played to its end
+    #[serde(default)]
+    pub last_played: Option<SystemTime>,
+    ///From 0 to `MAX_RATING` stars, rated while playing
+    #[serde(default)]
+    pub rating: Option<u8>,
+    ///Read from the tags of the file when the song is added or scanned
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub artist: Option<String>,
+    #[serde(default)]
+    pub album: Option<String>,
+    ///Fields of newer versions, see `Playlist`
+    #[serde(flatten)]
+    pub(crate) unknown: Map<String, Value>,
 }
 
 impl Song {
+    #[must_use]
     pub fn new(path: PathBuf) -> Song {
         Song {
             path,
             config: SongConfig::new(),
+            duration: None,
+            play_count: 0,
+            last_played: None,
+            rating: None,
+            title: None,
+            artist: None,
+            album: None,
+            unknown: Map::new(),
         }
     }
+    ///Unrated songs count as rated 0
+    #[must_use]
+    pub fn rated_at_least(&self, min: u8) -> bool {
+        self.rating.unwrap_or(0) >= min
+    }
+    pub(crate) fn has_tags(&self) -> bool {
+        self.title.is_some() || self.artist.is_some() || self.album.is_some()
+    }
+    pub(crate) fn set_tags(&mut self, tags: Tags) {
+        self.title = tags.title;
+        self.artist = tags.artist;
+        self.album = tags.album;
+    }
 }
 
+///"Artist - Title" if the tags tell, otherwise the file name
 impl fmt::Display for Song {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if let Some(title) = &self.title {
+            return match &self.artist {
+                Some(artist) => write!(f, "{artist} - {title}"),
+                None => write!(f, "{title}"),
+            };
+        }
         if let Some(s) = self.path.file_name() {
             if let Some(s) = s.to_str() {
                 return write!(f, "{s:}");
@@ -88,35 +480,107 @@ impl fmt::Display for Song {
 
 #[derive(Debug, PartialEq, Clone)]
 #[derive(Serialize, Deserialize)]
+#[serde(default)]
 pub struct SongConfig {
     pub volume: f32,
+    ///Playback speed factor. The pitch changes along with it, like a record played too fast.
+    pub speed: f32,
+    ///Skip the song up to this position
+    pub start: Option<Duration>,
+    ///Stop the song at this position
+    pub end: Option<Duration>,
+    ///Fields of newer versions, see `Playlist`
+    #[serde(flatten)]
+    pub(crate) unknown: Map<String, Value>,
 }
 
 impl SongConfig {
+    #[must_use]
     pub fn new() -> SongConfig {
-        SongConfig { volume: 1.0 }
+        SongConfig {
+            volume: 1.0,
+            speed: 1.0,
+            start: None,
+            end: None,
+            unknown: Map::new(),
+        }
+    }
+}
+
+impl Default for SongConfig {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 #[derive(Debug, PartialEq, Clone)]
 #[derive(Serialize, Deserialize)]
+#[serde(default)]
 #[allow(clippy::module_name_repetitions)]
 pub struct PlaylistConfig {
     pub volume: f32,
     pub random: RandomMode,
+    ///Fade in and out songs over this duration
+    pub fade: Option<Duration>,
+    ///Overlap consecutive songs by this duration
+    pub crossfade: Option<Duration>,
+    ///Silence between consecutive songs, unless crossfading
+    pub gap: Option<Duration>,
+    ///Playback tempo factor that keeps the pitch. Needs the `tempo` feature.
+    pub tempo: Option<f32>,
+    ///Softly compress peaks instead of clipping them, once songs are amplified above unity
+    pub limiter: bool,
+    ///Play the playlist in a loop, unless play is told otherwise
+    pub repeat: bool,
+    ///Fields of newer versions, see `Playlist`
+    #[serde(flatten)]
+    pub(crate) unknown: Map<String, Value>,
 }
 
 impl PlaylistConfig {
+    #[must_use]
     pub fn new() -> PlaylistConfig {
         PlaylistConfig {
             volume: 1.0,
             random: RandomMode::Off,
+            fade: None,
+            crossfade: None,
+            gap: None,
+            tempo: None,
+            limiter: false,
+            repeat: false,
+            unknown: Map::new(),
         }
     }
 }
 
+impl Default for PlaylistConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl fmt::Display for PlaylistConfig {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        write!(f, "Amplify: {}; Random mode: {}", self.volume, self.random)
+        write!(f, "Amplify: {}; Random mode: {}", self.volume, self.random)?;
+        if let Some(fade) = self.fade {
+            write!(f, "; Fade: {fade:?}")?;
+        }
+        if let Some(crossfade) = self.crossfade {
+            write!(f, "; Crossfade: {crossfade:?}")?;
+        }
+        if let Some(gap) = self.gap {
+            write!(f, "; Gap: {gap:?}")?;
+        }
+        if let Some(tempo) = self.tempo {
+            write!(f, "; Tempo: {tempo}x")?;
+        }
+        if self.limiter {
+            write!(f, "; Limiter")?;
+        }
+        if self.repeat {
+            write!(f, "; Repeat")?;
+        }
+        Ok(())
     }
 }
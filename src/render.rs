@@ -0,0 +1,161 @@
+use std::ffi::OsStr;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use hound::{SampleFormat, WavSpec, WavWriter};
+use rodio::source::UniformSourceIterator;
+
+use crate::controls::Playback;
+use crate::{audio, player, LibError};
+
+///Every song is converted to this, as a WAV file has a single format
+const SPEC: WavSpec = WavSpec {
+    channels: 2,
+    sample_rate: 44100,
+    bits_per_sample: 16,
+    sample_format: SampleFormat::Int,
+};
+
+///Write the songs one after another into a WAV file at `path`, as they would be played.
+///Songs play in the stored order, missing songs and songs that can't be decoded are left out.
+///A limit counts the songs from the start of the playlist, a preview shortens every song.
+///Returns how many songs could not be decoded.
+pub fn render(playback: &Playback, path: &Path) -> Result<usize, LibError> {
+    if !path
+        .extension()
+        .and_then(OsStr::to_str)
+        .is_some_and(|e| e.eq_ignore_ascii_case("wav"))
+    {
+        return Err(LibError::InvalidArgument(String::from(
+            "Only WAV files can be written, the output has to end in .wav",
+        )));
+    }
+    let playlist = &playback.playlist;
+    let config = &playlist.config;
+    if config.crossfade.is_some() {
+        eprintln!("Crossfading is not applied when writing to a file");
+    }
+    let mut writer = WavWriter::create(path, SPEC).map_err(wav_error)?;
+    let gap = config.gap.filter(|_| config.crossfade.is_none());
+    let mut failed = 0;
+    let mut first = true;
+    let limit = playback.limit.unwrap_or(usize::MAX);
+    for index in (0..playlist.song_count())
+        .filter(|i| !playback.skipped.contains(i))
+        .take(limit)
+    {
+        let song = &player::previewed(playlist.song(index).unwrap().clone(), playback.preview);
+        let source = match player::open_song(song, config) {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("{song}: {e}");
+                failed += 1;
+                continue;
+            }
+        };
+        if let Some(gap) = gap.filter(|_| !first) {
+            write_silence(&mut writer, gap)?;
+        }
+        first = false;
+        let source = audio::apply_config(source, &song.config, config, playback.max_volume);
+        let samples: UniformSourceIterator<_, i16> =
+            UniformSourceIterator::new(source, SPEC.channels, SPEC.sample_rate);
+        for sample in samples {
+            writer.write_sample(sample).map_err(wav_error)?;
+        }
+    }
+    writer.finalize().map_err(wav_error)?;
+    Ok(failed)
+}
+
+fn write_silence<W>(writer: &mut WavWriter<W>, duration: Duration) -> Result<(), LibError>
+where
+    W: io::Write + io::Seek,
+{
+    let frames = Duration::from_secs(1).as_nanos() / u128::from(SPEC.sample_rate);
+    let samples = duration.as_nanos() / frames * u128::from(SPEC.channels);
+    for _ in 0..samples {
+        writer.write_sample(0i16).map_err(wav_error)?;
+    }
+    Ok(())
+}
+
+fn wav_error(e: hound::Error) -> LibError {
+    match e {
+        hound::Error::IoError(e) => LibError::Io("Error writing WAV file", e),
+        e => LibError::Io("Error writing WAV file", io::Error::other(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::playlist::Playlist;
+
+    #[cfg(all(feature = "mp3", feature = "wav"))]
+    use {
+        crate::playlist::Song,
+        rodio::Source,
+        std::{fs, path::PathBuf},
+    };
+
+    #[test]
+    #[cfg(all(feature = "mp3", feature = "wav"))]
+    fn render_with_gap() {
+        let mut playlist = Playlist::new();
+        playlist.add_songs(vec![
+            Song::new(PathBuf::from("test_data/nested/silence.wav")),
+            Song::new(PathBuf::from("test_data/empty.playlist")),
+            Song::new(PathBuf::from("test_data/test.mp3")),
+        ]);
+        playlist.config.gap = Some(Duration::from_secs(1));
+        let playback = Playback::new(None, playlist);
+        let path = Path::new("test_render.wav");
+        let failed = render(&playback, path);
+        let reader = hound::WavReader::open(path);
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(failed.expect("Rendering should work"), 1);
+        let reader = reader.expect("Rendered file should be valid WAV");
+        assert_eq!(reader.spec(), SPEC);
+        // Reported durations of MP3 files are only estimates
+        let decoded = |path| {
+            let source = audio::open(Path::new(path)).unwrap();
+            let rate = f64::from(source.sample_rate()) * f64::from(source.channels());
+            #[allow(clippy::cast_precision_loss)]
+            let seconds = source.count() as f64 / rate;
+            seconds
+        };
+        let total = decoded("test_data/nested/silence.wav") + decoded("test_data/test.mp3") + 1.0;
+        let rendered = f64::from(reader.duration()) / f64::from(SPEC.sample_rate);
+        assert!((rendered - total).abs() < 0.01);
+    }
+
+    #[test]
+    #[cfg(all(feature = "mp3", feature = "wav"))]
+    fn render_preview() {
+        let mut playlist = Playlist::new();
+        playlist.add_songs(vec![
+            Song::new(PathBuf::from("test_data/test.mp3")),
+            Song::new(PathBuf::from("test_data/nested/silence.wav")),
+        ]);
+        let mut playback = Playback::new(None, playlist);
+        playback.preview = Some(Duration::from_millis(500));
+        let path = Path::new("test_render_preview.wav");
+        let failed = render(&playback, path);
+        let reader = hound::WavReader::open(path);
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(failed.expect("Rendering should work"), 0);
+        let rendered = f64::from(reader.unwrap().duration()) / f64::from(SPEC.sample_rate);
+        // The silence is shorter than the preview
+        assert!((rendered - 0.6).abs() < 0.01, "Rendered {rendered}s");
+    }
+
+    #[test]
+    fn render_only_wav() {
+        let playback = Playback::new(None, Playlist::new());
+        assert!(render(&playback, Path::new("test_render.flac")).is_err());
+    }
+}
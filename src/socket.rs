@@ -0,0 +1,110 @@
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::{fs, thread};
+
+use crate::audio::AudioSink;
+use crate::controls::{self, Playback};
+use crate::{status, LibError};
+
+///Accept connections on a Unix socket at `path` while playing.
+///Every connection gets all events as JSON lines, like with `play --json`,
+///and can send the same commands as `play --commands`, one per line.
+///`status` is answered with a status event on that connection only.
+pub fn listen(
+    path: &Path, sink: &Arc<dyn AudioSink>, playback: &Arc<Mutex<Playback>>,
+) -> Result<(), LibError> {
+    // Left over from an earlier run that didn't exit cleanly
+    let stale = fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket());
+    if stale && UnixStream::connect(path).is_err() {
+        fs::remove_file(path).map_err(|e| LibError::Io("Error removing old socket", e))?;
+    }
+    let listener = UnixListener::bind(path).map_err(|e| LibError::Io("Error opening socket", e))?;
+    let sink = sink.clone();
+    let playback = playback.clone();
+    // Not joined, it blocks on accepting until the program exits
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else {
+                continue;
+            };
+            let Ok(writer) = stream.try_clone() else {
+                continue;
+            };
+            playback.lock().unwrap().listeners.add(Box::new(writer));
+            let sink = sink.clone();
+            let playback = playback.clone();
+            thread::spawn(move || serve(stream, &*sink, &playback));
+        }
+    });
+    Ok(())
+}
+
+fn serve(stream: UnixStream, sink: &dyn AudioSink, playback: &Mutex<Playback>) {
+    let Ok(mut writer) = stream.try_clone() else {
+        return;
+    };
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        let reply = match line.trim() {
+            "" => continue,
+            "status" => current_status(sink, &playback.lock().unwrap()),
+            line => match controls::run_command(sink, playback, line) {
+                Ok(()) => continue,
+                Err(e) => serde_json::to_string(&status::Event::CommandError { message: &e }),
+            },
+        };
+        // Only strings and numbers, so this can't fail
+        if writeln!(writer, "{}", reply.unwrap()).is_err() {
+            break;
+        }
+    }
+}
+
+fn current_status(sink: &dyn AudioSink, playback: &Playback) -> serde_json::Result<String> {
+    let song = playback.song.and_then(|i| playback.playlist.song(i));
+    serde_json::to_string(&status::Event::Status {
+        index: playback.song,
+        name: song.map(ToString::to_string),
+        position: sink.get_pos().as_secs_f64(),
+        paused: sink.is_paused(),
+        muted: playback.muted,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::FakeSink;
+    use crate::playlist::Playlist;
+
+    #[test]
+    fn status_over_socket() {
+        let path = Path::new("test_status.sock");
+        let sink: Arc<dyn AudioSink> = Arc::new(FakeSink::default());
+        let playback = Arc::new(Mutex::new(Playback::new(None, Playlist::new())));
+        listen(path, &sink, &playback).unwrap();
+
+        let stream = UnixStream::connect(path).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        writeln!(writer, "pause\nstatus\nfly").unwrap();
+        let mut status = String::new();
+        reader.read_line(&mut status).unwrap();
+        let mut error = String::new();
+        reader.read_line(&mut error).unwrap();
+        fs::remove_file(path).unwrap();
+
+        assert!(sink.is_paused());
+        let status: serde_json::Value = serde_json::from_str(&status).unwrap();
+        assert_eq!(status["event"], "status");
+        assert_eq!(status["paused"], true);
+        assert_eq!(status["index"], serde_json::Value::Null);
+        let error: serde_json::Value = serde_json::from_str(&error).unwrap();
+        assert_eq!(error["event"], "command_error");
+    }
+}
@@ -0,0 +1,147 @@
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+
+///Events printed with `play --json`, one JSON object per line on stdout.
+///The `event` field names the kind of event. The schema is stable,
+///new fields and events may be added, existing ones are not renamed or removed.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    ///`{"event":"song_start","index":0,"path":"music/a.mp3","name":"a.mp3"}`
+    SongStart {
+        ///Index of the song in the playlist
+        index: usize,
+        path: String,
+        ///As displayed by `display`
+        name: String,
+    },
+    ///`{"event":"volume","volume":0.5,"limited":false}`
+    Volume {
+        ///Combined song and playlist volume that is played
+        volume: f32,
+        ///Whether the volume is capped by `--max-volume`
+        limited: bool,
+    },
+    ///`{"event":"error","index":0,"message":"..."}`
+    Error {
+        ///Index of the song that could not be played
+        index: usize,
+        message: &'a str,
+    },
+    ///`{"event":"done","stopped":false,"failed_songs":0}`, always the last event
+    Done {
+        ///Whether playback was stopped before the playlist ended
+        stopped: bool,
+        failed_songs: usize,
+    },
+    ///`{"event":"status","index":0,"name":"a.mp3","position":12.5,"paused":false,"muted":false}`,
+    ///only sent on request
+    Status {
+        ///Index of the current song, none before the first song started
+        index: Option<usize>,
+        name: Option<String>,
+        ///Seconds into the current song
+        position: f64,
+        paused: bool,
+        muted: bool,
+    },
+    ///`{"event":"command_error","message":"..."}`, sent back for a command that failed
+    CommandError { message: &'a str },
+}
+
+pub fn emit(event: &Event) {
+    println!("{}", to_line(event));
+}
+
+fn to_line(event: &Event) -> String {
+    // Only strings and numbers, so this can't fail
+    serde_json::to_string(event).unwrap()
+}
+
+///Connections that get every event as a line of JSON, like the clients of `play --socket`
+#[derive(Clone, Default)]
+pub struct Listeners(Arc<Mutex<Vec<Box<dyn Write + Send>>>>);
+
+impl Listeners {
+    pub fn add(&self, listener: Box<dyn Write + Send>) {
+        self.0.lock().unwrap().push(listener);
+    }
+
+    ///Listeners that can't be written to anymore are dropped
+    pub fn send(&self, event: &Event) {
+        let mut listeners = self.0.lock().unwrap();
+        if listeners.is_empty() {
+            return;
+        }
+        let line = to_line(event);
+        listeners.retain_mut(|l| writeln!(l, "{line}").and_then(|()| l.flush()).is_ok());
+    }
+}
+
+///Shares the written bytes with the test
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl Buffer {
+    pub fn text(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+#[cfg(test)]
+impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stable_schema() {
+        let start = Event::SongStart {
+            index: 1,
+            path: String::from("music/a.mp3"),
+            name: String::from("a.mp3"),
+        };
+        assert_eq!(
+            serde_json::to_string(&start).unwrap(),
+            r#"{"event":"song_start","index":1,"path":"music/a.mp3","name":"a.mp3"}"#
+        );
+        let done = Event::Done {
+            stopped: false,
+            failed_songs: 2,
+        };
+        assert_eq!(
+            serde_json::to_string(&done).unwrap(),
+            r#"{"event":"done","stopped":false,"failed_songs":2}"#
+        );
+    }
+
+    #[test]
+    fn send_to_listeners() {
+        let listeners = Listeners::default();
+        let buffer = Buffer::default();
+        listeners.add(Box::new(buffer.clone()));
+        listeners.send(&Event::Done {
+            stopped: true,
+            failed_songs: 0,
+        });
+        listeners.send(&Event::CommandError { message: "x" });
+        assert_eq!(
+            buffer.text(),
+            "{\"event\":\"done\",\"stopped\":true,\"failed_songs\":0}\n\
+             {\"event\":\"command_error\",\"message\":\"x\"}\n"
+        );
+    }
+}
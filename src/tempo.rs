@@ -0,0 +1,260 @@
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+use std::time::Duration;
+
+use rodio::source::SeekError;
+use rodio::Source;
+
+///Frames per segment, about 46ms at 44.1kHz
+const SEGMENT: usize = 2048;
+///Segments overlap by half, so the windows add up to 1
+const HOP: usize = SEGMENT / 2;
+///How many frames a segment may be moved to line up with the previous one
+const TOLERANCE: usize = 512;
+///Only every nth frame is compared when lining up segments, to keep it cheap
+const STRIDE: usize = 4;
+
+///Changes the tempo of the input without changing its pitch (WSOLA).
+///Segments of the input are overlapped at a different distance than they were taken at,
+///each moved slightly to line up with the previous one to avoid phasing.
+///Positions and durations are those of the stretched output.
+pub struct TimeStretch<I> {
+    input: I,
+    tempo: f32,
+    channels: usize,
+    window: Vec<f32>,
+    ///Interleaved input, starting at frame `offset`. Starts with `HOP` frames of silence.
+    buffer: Vec<f32>,
+    offset: usize,
+    input_ended: bool,
+    ///Where the next segment would be taken without lining it up
+    next: f64,
+    ///Where the last segment would have continued
+    natural: Option<usize>,
+    ///Segments added up, the first `HOP` frames are complete
+    overlap: Vec<f32>,
+    ready: VecDeque<i16>,
+    ///Output samples to drop, covering the silence at the start
+    skip: usize,
+    ///Frames read from the input
+    read: usize,
+    ///Samples written to the output
+    written: usize,
+    done: bool,
+}
+
+impl<I> TimeStretch<I>
+where
+    I: Source<Item = i16>,
+{
+    #[allow(clippy::cast_precision_loss)]
+    pub fn new(input: I, tempo: f32) -> Self {
+        let channels = usize::from(input.channels().max(1));
+        let window = (0..SEGMENT)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / SEGMENT as f32).cos())
+            .collect();
+        let mut stretch = TimeStretch {
+            input,
+            tempo,
+            channels,
+            window,
+            buffer: vec![],
+            offset: 0,
+            input_ended: false,
+            next: 0.0,
+            natural: None,
+            overlap: vec![],
+            ready: VecDeque::new(),
+            skip: 0,
+            read: 0,
+            written: 0,
+            done: false,
+        };
+        stretch.reset();
+        stretch
+    }
+
+    fn reset(&mut self) {
+        self.buffer = vec![0.0; HOP * self.channels];
+        self.offset = 0;
+        self.input_ended = false;
+        self.next = 0.0;
+        self.natural = None;
+        self.overlap = vec![0.0; SEGMENT * self.channels];
+        self.ready.clear();
+        self.skip = HOP * self.channels;
+        self.read = 0;
+        self.written = 0;
+        self.done = false;
+    }
+
+    fn buffered_frames(&self) -> usize {
+        self.offset + self.buffer.len() / self.channels
+    }
+
+    ///Read input until frame `end` is buffered
+    fn fill(&mut self, end: usize) {
+        while !self.input_ended && self.buffered_frames() < end {
+            for c in 0..self.channels {
+                if let Some(s) = self.input.next() {
+                    self.buffer.push(f32::from(s));
+                } else {
+                    self.input_ended = true;
+                    // Complete the frame so channels stay lined up
+                    if c > 0 {
+                        self.buffer
+                            .resize(self.buffer.len() + self.channels - c, 0.0);
+                    }
+                    break;
+                }
+            }
+            self.read += 1;
+        }
+    }
+
+    ///Silence outside the buffer
+    fn sample(&self, frame: usize, channel: usize) -> f32 {
+        frame
+            .checked_sub(self.offset)
+            .and_then(|f| self.buffer.get(f * self.channels + channel))
+            .copied()
+            .unwrap_or(0.0)
+    }
+
+    fn mono(&self, frame: usize) -> f32 {
+        (0..self.channels).map(|c| self.sample(frame, c)).sum()
+    }
+
+    ///Start of the segment near `ideal` that continues most like `natural`
+    fn line_up(&self, natural: usize, ideal: usize) -> usize {
+        let start = ideal.saturating_sub(TOLERANCE).max(self.offset);
+        let coarse = self.best_match(natural, (start..=ideal + TOLERANCE).step_by(STRIDE));
+        let start = coarse.saturating_sub(STRIDE).max(self.offset);
+        self.best_match(natural, start..=coarse + STRIDE)
+    }
+
+    fn best_match(&self, natural: usize, candidates: impl Iterator<Item = usize>) -> usize {
+        let mut best = natural;
+        let mut best_score = f32::MIN;
+        for candidate in candidates {
+            let score: f32 = (0..HOP)
+                .step_by(STRIDE)
+                .map(|i| self.mono(natural + i) * self.mono(candidate + i))
+                .sum();
+            if score > best_score {
+                best = candidate;
+                best_score = score;
+            }
+        }
+        best
+    }
+
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_precision_loss,
+        clippy::cast_sign_loss
+    )]
+    fn add_segment(&mut self) {
+        let ideal = self.next.round() as usize;
+        self.fill(ideal + TOLERANCE + SEGMENT);
+        if self.input_ended && ideal >= self.buffered_frames() {
+            // Only the fading end of the last segment is left
+            self.emit(HOP);
+            self.done = true;
+            return;
+        }
+
+        let start = match self.natural {
+            Some(natural) => self.line_up(natural, ideal),
+            None => ideal,
+        };
+        for (i, w) in self.window.iter().enumerate() {
+            for c in 0..self.channels {
+                self.overlap[i * self.channels + c] += self.sample(start + i, c) * w;
+            }
+        }
+        self.emit(HOP);
+
+        self.natural = Some(start + HOP);
+        self.next += HOP as f64 * f64::from(self.tempo);
+        // Keep what the next segment may still need
+        let keep = (self.next as usize)
+            .saturating_sub(TOLERANCE)
+            .min(start + HOP);
+        if keep > self.offset {
+            let drop = (keep - self.offset).min(self.buffer.len() / self.channels);
+            self.buffer.drain(..drop * self.channels);
+            self.offset += drop;
+        }
+    }
+
+    ///Move the first `frames` of the overlap to the output.
+    ///Once the input ended, the output ends at its stretched length instead of fading out.
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_precision_loss,
+        clippy::cast_sign_loss
+    )]
+    fn emit(&mut self, frames: usize) {
+        let samples = frames * self.channels;
+        let end = (self.read as f64 / f64::from(self.tempo)).round() as usize * self.channels;
+        for s in self.overlap.drain(..samples) {
+            if self.skip > 0 {
+                self.skip -= 1;
+            } else if self.input_ended && self.written >= end {
+                self.done = true;
+            } else {
+                self.ready
+                    .push_back(s.round().clamp(-32768.0, 32767.0) as i16);
+                self.written += 1;
+            }
+        }
+        self.overlap.resize(SEGMENT * self.channels, 0.0);
+    }
+}
+
+impl<I> Iterator for TimeStretch<I>
+where
+    I: Source<Item = i16>,
+{
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        loop {
+            if let Some(s) = self.ready.pop_front() {
+                return Some(s);
+            }
+            if self.done {
+                return None;
+            }
+            self.add_segment();
+        }
+    }
+}
+
+impl<I> Source for TimeStretch<I>
+where
+    I: Source<Item = i16>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.input.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration().map(|d| d.div_f32(self.tempo))
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.input.try_seek(pos.mul_f32(self.tempo))?;
+        self.reset();
+        Ok(())
+    }
+}